@@ -0,0 +1,150 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Platform-independent descriptions of the graphics contexts to create.
+
+use std::default::Default;
+
+/// The framebuffer a context renders into. Each backend translates this into its own visual or
+/// pixel format attributes, and reports what it actually granted through
+/// `GraphicsContextMethods::pixel_format()`.
+#[deriving(Clone, PartialEq, Show)]
+pub struct PixelFormat {
+    /// Bits in the red channel.
+    pub red_bits: u8,
+    /// Bits in the green channel.
+    pub green_bits: u8,
+    /// Bits in the blue channel.
+    pub blue_bits: u8,
+    /// Bits in the alpha channel. Zero if there is no alpha channel.
+    pub alpha_bits: u8,
+    /// Bits in the depth buffer. Zero if there is no depth buffer.
+    pub depth_bits: u8,
+    /// Bits in the stencil buffer. Zero if there is no stencil buffer.
+    pub stencil_bits: u8,
+    /// Samples per pixel. Zero if the framebuffer is not multisampled.
+    pub samples: u8,
+    /// Whether the framebuffer is capable of sRGB encoding.
+    pub srgb: bool,
+    /// Whether the framebuffer has separate front and back buffers.
+    pub double_buffered: bool,
+}
+
+impl Default for PixelFormat {
+    /// Returns 8-bit RGB without alpha and with a 24-bit depth buffer, single-buffered and without
+    /// multisampling. Backends that have always created their default contexts with a different
+    /// framebuffer start from their own format in `GraphicsContextMethods::try_new()` instead.
+    fn default() -> PixelFormat {
+        PixelFormat {
+            red_bits: 8,
+            green_bits: 8,
+            blue_bits: 8,
+            alpha_bits: 0,
+            depth_bits: 24,
+            stencil_bits: 0,
+            samples: 0,
+            srgb: false,
+            double_buffered: false,
+        }
+    }
+}
+
+impl PixelFormat {
+    /// Returns the number of bits in all color channels together, including alpha.
+    pub fn color_bits(&self) -> u8 {
+        self.red_bits + self.green_bits + self.blue_bits + self.alpha_bits
+    }
+}
+
+/// The client API a context implements.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Api {
+    /// Desktop OpenGL.
+    OpenGL,
+    /// OpenGL ES.
+    OpenGLES,
+}
+
+/// The OpenGL profile a context implements. Profiles only exist for OpenGL 3.2 and later.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Profile {
+    /// The core profile, without deprecated functionality.
+    CoreProfile,
+    /// The compatibility profile, which keeps deprecated functionality.
+    CompatibilityProfile,
+}
+
+/// Everything that can be requested when creating a graphics context.
+#[deriving(Clone, PartialEq, Show)]
+pub struct ContextAttributes {
+    /// The framebuffer to render into.
+    pub pixel_format: PixelFormat,
+    /// The client API.
+    pub api: Api,
+    /// The minimum major and minor version of the API, or `None` to accept whatever version the
+    /// platform creates by default.
+    pub version: Option<(u8, u8)>,
+    /// The OpenGL profile. Ignored for OpenGL ES and for OpenGL versions before 3.2.
+    pub profile: Profile,
+    /// Whether functionality deprecated in the requested version should be removed.
+    pub forward_compatible: bool,
+    /// Whether to create a debug context. CGL has no debug contexts, so asking for one on Mac
+    /// fails with `ExtensionUnsupported`.
+    pub debug: bool,
+}
+
+impl ContextAttributes {
+    /// Returns true if these attributes can be satisfied by the platform's legacy context
+    /// creation call, without asking for a specific version, profile or flags.
+    pub fn is_legacy(&self) -> bool {
+        self.api == OpenGL && self.version.is_none() && !self.forward_compatible && !self.debug
+    }
+
+    /// Returns true if a core profile is being requested: OpenGL 3.2 or later, core profile.
+    pub fn wants_core_profile(&self) -> bool {
+        match (self.api, self.version) {
+            (OpenGL, Some(version)) => version >= (3, 2) && self.profile == CoreProfile,
+            _ => false,
+        }
+    }
+}
+
+impl Default for ContextAttributes {
+    fn default() -> ContextAttributes {
+        ContextAttributes {
+            pixel_format: Default::default(),
+            api: OpenGL,
+            version: None,
+            profile: CompatibilityProfile,
+            forward_compatible: false,
+            debug: false,
+        }
+    }
+}
+
+#[test]
+fn core_profile_requires_gl_3_2() {
+    let mut attributes: ContextAttributes = Default::default();
+    attributes.profile = CoreProfile;
+    assert!(attributes.is_legacy() && !attributes.wants_core_profile());
+    attributes.version = Some((3, 1));
+    assert!(!attributes.is_legacy() && !attributes.wants_core_profile());
+    attributes.version = Some((3, 2));
+    assert!(attributes.wants_core_profile());
+    attributes.api = OpenGLES;
+    assert!(!attributes.wants_core_profile());
+}
+
+#[test]
+fn default_pixel_format_is_rgb_with_a_depth_buffer() {
+    let pixel_format: PixelFormat = Default::default();
+    assert_eq!(pixel_format.color_bits(), 24);
+    assert_eq!(pixel_format.depth_bits, 24);
+    assert!(!pixel_format.double_buffered);
+}
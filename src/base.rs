@@ -7,20 +7,251 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use error::Error;
+use fence::SyncToken;
+
 use geom::size::Size2D;
+use opengles::gl2::GLuint;
+use serialize::json;
+use std::str;
+
+/// The DRM format modifier of a buffer whose layout the driver did not report, in which case the
+/// importer must assume the driver's implicit layout.
+pub static DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+
+/// One plane of a buffer shared as dma-bufs.
+#[deriving(Clone, PartialEq, Eq, Hash, Show, Encodable, Decodable)]
+pub struct DmaBufPlane {
+    /// The dma-buf file descriptor holding the plane. This only names the descriptor: it is owned
+    /// by whoever exported the buffer, or by the `FileDescriptor` it was received into.
+    pub fd: int,
+    /// The offset of the plane within the dma-buf in bytes.
+    pub offset: u32,
+    /// The number of bytes between the starts of consecutive rows.
+    pub stride: u32,
+}
+
+/// A buffer shared as dma-bufs, together with the layout an importer needs.
+#[deriving(Clone, PartialEq, Eq, Hash, Show, Encodable, Decodable)]
+pub struct DmaBuf {
+    /// The DRM fourcc code of the pixel format, such as `AB24` for `DRM_FORMAT_ABGR8888`.
+    pub fourcc: u32,
+    /// The DRM format modifier describing the tiling of every plane, or `DRM_FORMAT_MOD_INVALID`.
+    pub modifier: u64,
+    /// The planes, at most four.
+    pub planes: Vec<DmaBufPlane>,
+}
+
+/// Names a POSIX shared-memory region by the process that created it and a serial number that is
+/// unique within that process.
+#[deriving(Clone, PartialEq, Eq, Hash, Show, Encodable, Decodable)]
+pub struct ShmRegionId {
+    /// The ID of the creating process.
+    pub pid: i32,
+    /// The number of regions the creating process had created before this one.
+    pub serial: u64,
+}
+
+/// Names a shared surface in a way that can be sent to another process. Each backend produces one
+/// kind of ID, so a reader can tell an ID from an incompatible backend apart from a valid one.
+#[deriving(Clone, PartialEq, Eq, Hash, Show, Encodable, Decodable)]
+pub enum SurfaceId {
+    /// An IOSurface, by its global `IOSurfaceID`.
+    IOSurfaceId(u32),
+    /// An X11 drawable, by its XID on the display the producer connected to.
+    X11DrawableId(u64),
+    /// A buffer exported as dma-bufs. The file descriptors belong to the producing process; send
+    /// them over a Unix socket and substitute the received ones before opening.
+    DmaBufId(DmaBuf),
+    /// A POSIX shared-memory region.
+    ShmId(ShmRegionId),
+    /// A surface of the dummy backend, which has no contents.
+    DummyId(uint),
+}
+
+impl SurfaceId {
+    /// Serializes the ID into bytes for sending to another process.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        json::encode(self).into_bytes()
+    }
+
+    /// Deserializes an ID produced by `to_bytes()`. Returns `None` if the bytes are not a valid ID.
+    pub fn from_bytes(bytes: &[u8]) -> Option<SurfaceId> {
+        match str::from_utf8(bytes) {
+            Some(string) => json::decode(string).ok(),
+            None => None,
+        }
+    }
+}
+
+/// The layout of the pixels in a shared surface.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub enum SurfaceFormat {
+    /// 8 bits per channel, red first in memory.
+    RGBA8Format,
+    /// 8 bits per channel, blue first in memory.
+    BGRA8Format,
+}
+
+impl SurfaceFormat {
+    /// Returns the number of bytes each pixel occupies.
+    pub fn bytes_per_pixel(&self) -> uint {
+        match *self {
+            RGBA8Format | BGRA8Format => 4,
+        }
+    }
+}
+
+/// Everything a consumer needs to open a shared surface, as a value that can be serialized and
+/// sent to the consuming process.
+#[deriving(Clone, PartialEq, Show, Encodable, Decodable)]
+pub struct SurfaceDescriptor {
+    /// The ID to pass to `SharedSurfaceReader::open()`.
+    pub id: SurfaceId,
+    /// The number of times the surface has been reallocated.
+    pub generation: uint,
+    /// The width of the surface in pixels.
+    pub width: int,
+    /// The height of the surface in pixels.
+    pub height: int,
+    /// The layout of the pixels.
+    pub format: SurfaceFormat,
+    /// The number of bytes between the starts of consecutive rows.
+    pub stride: uint,
+}
+
+impl SurfaceDescriptor {
+    /// Returns the size of the surface.
+    pub fn size(&self) -> Size2D<int> {
+        Size2D(self.width, self.height)
+    }
+}
 
 pub trait ShareContext {
-    // Creates a new context for GL object sharing.
-    fn new(size: Size2D<int>) -> Self;
+    // Creates a new context for GL object sharing, or returns the reason it could not be created.
+    fn try_new(size: Size2D<int>) -> Result<Self,Error>;
 
-    // Flushes the context.
-    fn flush(&self);
+    // Creates a new context for GL object sharing. Fails if the context cannot be created.
+    fn new(size: Size2D<int>) -> Self {
+        match ShareContext::try_new(size) {
+            Ok(context) => context,
+            Err(error) => fail!("failed to create share context: {}", error),
+        }
+    }
+
+    // Reallocates the shared surface at the given size and points rendering at it, or returns the
+    // reason it could not be resized. Afterwards `id()` may name a different surface and
+    // `generation()` has changed, so consumers must reopen the surface with the new ID and size.
+    // The contents of the surface are undefined until the next frame is rendered.
+    fn try_resize(&mut self, size: Size2D<int>) -> Result<(),Error>;
+
+    // Resizes the shared surface. Fails if the surface cannot be reallocated.
+    fn resize(&mut self, size: Size2D<int>) {
+        match self.try_resize(size) {
+            Ok(()) => {}
+            Err(error) => fail!("failed to resize share context: {}", error),
+        }
+    }
+
+    // Submits the rendering done so far and returns a token that the consumer waits on before
+    // reading the frame. Where the platform has no cross-process fences, this blocks until
+    // rendering has finished and returns `FinishedSync`.
+    fn flush(&self) -> SyncToken;
 
     // Returns the platform-specific ID that can be passed to other processes to access the shared
     // resources.
-    fn id(&self) -> int;
+    fn id(&self) -> SurfaceId;
+
+    // Returns the number of times the surface has been reallocated by `resize()`. Announce it
+    // along with the ID so that consumers can tell when to reopen the surface.
+    fn generation(&self) -> uint;
+
+    // Returns the size of the shared surface in pixels.
+    fn size(&self) -> Size2D<int>;
+
+    // Returns the layout of the pixels in the shared surface.
+    fn format(&self) -> SurfaceFormat;
+
+    // Returns the framebuffer object that rendering goes to, or 0 if the context renders into the
+    // default framebuffer of its drawable.
+    fn framebuffer(&self) -> GLuint {
+        0
+    }
+
+    // Returns the number of bytes between the starts of consecutive rows of the shared surface.
+    fn stride(&self) -> uint {
+        self.size().width as uint * self.format().bytes_per_pixel()
+    }
+
+    // Returns a description of the shared surface to send to consumers.
+    fn descriptor(&self) -> SurfaceDescriptor {
+        let size = self.size();
+        SurfaceDescriptor {
+            id: self.id(),
+            generation: self.generation(),
+            width: size.width,
+            height: size.height,
+            format: self.format(),
+            stride: self.stride(),
+        }
+    }
+}
+
+pub trait SharedSurfaceReader<GraphicsContextType> {
+    // Attaches to the surface with the given ID, as returned by `ShareContext::id()` in the
+    // producing process, and binds it to a new texture in the given context. Returns the reason
+    // on failure, which is `IncompatibleSurface` if the ID comes from a different backend.
+    fn try_open(context: &GraphicsContextType, id: &SurfaceId, size: Size2D<int>)
+                -> Result<Self,Error>;
+
+    // Attaches to the surface with the given ID. Fails if the surface cannot be attached.
+    fn open(context: &GraphicsContextType, id: &SurfaceId, size: Size2D<int>) -> Self {
+        match SharedSurfaceReader::try_open(context, id, size) {
+            Ok(reader) => reader,
+            Err(error) => fail!("failed to open shared surface {}: {}", id, error),
+        }
+    }
+
+    // Returns the texture in the receiving context that holds the shared surface.
+    fn texture(&self) -> GLuint;
+
+    // Brings the texture up to date with the latest flushed contents of the shared surface.
+    fn update(&self);
+
+    // Reads back the contents of the shared surface as tightly packed RGBA pixels.
+    fn read_pixels(&self) -> Vec<u8>;
 }
 
 #[test]
 fn smoke() {}
 
+#[test]
+fn descriptors_survive_serialization() {
+    use serialize::json;
+
+    let descriptor = SurfaceDescriptor {
+        id: X11DrawableId(42),
+        generation: 3,
+        width: 640,
+        height: 480,
+        format: BGRA8Format,
+        stride: 640 * 4,
+    };
+    let encoded = json::encode(&descriptor);
+    assert_eq!(json::decode::<SurfaceDescriptor>(encoded.as_slice()).unwrap(), descriptor);
+}
+
+#[test]
+fn surface_ids_survive_serialization_to_bytes() {
+    let buffer = DmaBuf {
+        fourcc: 0x34324241,
+        modifier: DRM_FORMAT_MOD_INVALID,
+        planes: vec!(DmaBufPlane { fd: 3, offset: 0, stride: 64 }),
+    };
+    let region = ShmRegionId { pid: 1, serial: 0 };
+    let ids = [ IOSurfaceId(7), DmaBufId(buffer), ShmId(region), DummyId(0) ];
+    for id in ids.iter() {
+        assert_eq!(SurfaceId::from_bytes(id.to_bytes().as_slice()), Some(id.clone()));
+    }
+    assert_eq!(SurfaceId::from_bytes(b"garbage"), None);
+}
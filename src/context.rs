@@ -8,8 +8,20 @@
 // except according to those terms.
 
 //! A platform-independent interface to 3D graphics contexts.
+//!
+//! Graphics contexts are `Send` but not `Sync`: a context may move to another thread, but a
+//! handle cannot be shared between threads, because a native context may only be current on one
+//! thread at a time. To move a context that is current, wrap it in a `ContextHandoff`, which
+//! releases it on the sending thread, and call `acquire()` on the receiving thread. With GLX, the
+//! embedder must have called `init_xlib_threads()` first. Clones share the native context, so only
+//! one of them should be current at any time.
 
-use sync::Arc;
+use attributes::{ContextAttributes, PixelFormat};
+use error::Error;
+
+use libc::c_void;
+use std::default::Default;
+use sync::{Arc, Weak};
 
 /// Platform-independent interface to 3D graphics contexts.
 pub trait GraphicsContextMethods<NativeContextType> {
@@ -19,13 +31,180 @@ pub trait GraphicsContextMethods<NativeContextType> {
     /// Returns the underlying native 3D context.
     fn native(&self) -> Arc<NativeContextType>;
 
-    /// Creates a new offscreen 3D graphics context.
-    fn new() -> Self;
+    /// Creates a new offscreen 3D graphics context with the given attributes, or returns the
+    /// reason it could not be created.
+    fn try_new_with_attributes(attributes: &ContextAttributes) -> Result<Self,Error>;
+
+    /// Creates a new offscreen 3D graphics context with the given attributes, shared with this
+    /// context, or returns the reason it could not be created.
+    fn try_new_shared_with_attributes(&self, attributes: &ContextAttributes) -> Result<Self,Error>;
+
+    /// Makes this context the current context, so that all graphics operations will go here, or
+    /// returns the reason it could not be made current.
+    fn try_make_current(&self) -> Result<(),Error>;
+
+    /// Leaves no context current on this thread if this context is current, or returns the reason
+    /// it could not be released. Does nothing if another context, or none, is current.
+    fn try_release_current(&self) -> Result<(),Error>;
+
+    /// Returns true if this context is the current context on this thread.
+    fn is_current(&self) -> bool;
+
+    /// Returns the context that is current on this thread, or `None` if there is none. A context
+    /// made current by code outside this crate is returned together with the drawables or buffer
+    /// it is current on, so that making it current again restores exactly that state.
+    fn current() -> Option<Self>;
+
+    /// Returns the pixel format the platform actually granted, which may differ from the one
+    /// requested.
+    fn pixel_format(&self) -> PixelFormat;
+
+    /// Returns the address of the named GL function as implemented by this context, or `None` if
+    /// the platform does not provide it. Some platforms return an address for any name, so callers
+    /// should check the GL version or extensions first when it matters.
+    fn get_proc_address(&self, name: &str) -> Option<*const c_void>;
 
-    /// Creates a new offscreen 3D graphics context shared with the given context.
-    fn new_shared(share_context: Self) -> Self;
+    /// Creates a new offscreen 3D graphics context with the backend's default attributes, or
+    /// returns the reason it could not be created. Backends whose default framebuffer differs from
+    /// `ContextAttributes::default()` override this and `try_new_shared()`.
+    fn try_new() -> Result<Self,Error> {
+        GraphicsContextMethods::try_new_with_attributes(&Default::default())
+    }
+
+    /// Creates a new offscreen 3D graphics context with the backend's default attributes, shared
+    /// with this context, or returns the reason it could not be created.
+    fn try_new_shared(&self) -> Result<Self,Error> {
+        self.try_new_shared_with_attributes(&Default::default())
+    }
+
+    /// Creates a new offscreen 3D graphics context. Fails if the context cannot be created.
+    fn new() -> Self {
+        match GraphicsContextMethods::try_new() {
+            Ok(context) => context,
+            Err(error) => fail!("failed to create graphics context: {}", error),
+        }
+    }
+
+    /// Creates a new offscreen 3D graphics context with the given attributes. Fails if the context
+    /// cannot be created.
+    fn new_with_attributes(attributes: &ContextAttributes) -> Self {
+        match GraphicsContextMethods::try_new_with_attributes(attributes) {
+            Ok(context) => context,
+            Err(error) => fail!("failed to create graphics context: {}", error),
+        }
+    }
+
+    /// Creates a new offscreen 3D graphics context shared with this context. Fails if the context
+    /// cannot be created.
+    fn new_shared(&self) -> Self {
+        match self.try_new_shared() {
+            Ok(context) => context,
+            Err(error) => fail!("failed to create shared graphics context: {}", error),
+        }
+    }
 
     /// Makes this context the current context, so that all graphics operations will go here.
-    fn make_current(&self);
+    /// Fails if the context cannot be made current.
+    fn make_current(&self) {
+        match self.try_make_current() {
+            Ok(()) => {}
+            Err(error) => fail!("failed to make graphics context current: {}", error),
+        }
+    }
+
+    /// Leaves no context current on this thread if this context is current. Fails if the context
+    /// cannot be released.
+    fn release_current(&self) {
+        match self.try_release_current() {
+            Ok(()) => {}
+            Err(error) => fail!("failed to release graphics context: {}", error),
+        }
+    }
+}
+
+/// The context a backend last made current on this task, so that `current()` can return it with
+/// the resources it renders into rather than treating it as a foreign context. Each backend keeps
+/// one in a task-local, set by `try_make_current()` and cleared by `try_release_current()`.
+pub struct CurrentContext<R,N> {
+    resources: Weak<R>,
+    context: Weak<N>,
+}
+
+impl<R:Send + Sync,N:PartialEq + Send + Sync> CurrentContext<R,N> {
+    /// Records the resources and native context of a context that is being made current.
+    pub fn new(resources: &Arc<R>, context: &Arc<N>) -> CurrentContext<R,N> {
+        CurrentContext {
+            resources: resources.downgrade(),
+            context: context.downgrade(),
+        }
+    }
+
+    /// Returns the recorded resources and native context if the context is still alive and is
+    /// the given native context, which the platform reports as current.
+    pub fn upgrade(&self, native: &N) -> Option<(Arc<R>, Arc<N>)> {
+        match (self.resources.upgrade(), self.context.upgrade()) {
+            (Some(resources), Some(context)) if *context == *native => Some((resources, context)),
+            _ => None,
+        }
+    }
+}
+
+/// Makes a context current for as long as the guard lives. When the guard is dropped, whichever
+/// context was current before is made current again, or no context if there was none.
+pub struct CurrentGuard<'a,C:'a> {
+    context: &'a C,
+    previous: Option<C>,
+}
+
+impl<'a,N,C:GraphicsContextMethods<N>> CurrentGuard<'a,C> {
+    /// Makes the given context current, or returns the reason it could not be made current.
+    pub fn new(context: &'a C) -> Result<CurrentGuard<'a,C>,Error> {
+        let previous = GraphicsContextMethods::current();
+        try!(context.try_make_current());
+        Ok(CurrentGuard {
+            context: context,
+            previous: previous,
+        })
+    }
+}
+
+/// A context that has been released on one thread so that it can be made current on another.
+pub struct ContextHandoff<C> {
+    context: C,
+}
+
+impl<N,C:GraphicsContextMethods<N> + Send> ContextHandoff<C> {
+    /// Releases the context if it is current on this thread. If it could not be released, returns
+    /// the context, still current, together with the reason.
+    pub fn new(context: C) -> Result<ContextHandoff<C>,(C,Error)> {
+        match context.try_release_current() {
+            Ok(()) => {}
+            Err(error) => return Err((context, error)),
+        }
+        Ok(ContextHandoff {
+            context: context,
+        })
+    }
+
+    /// Makes the context current on this thread and returns it, or returns the reason it could
+    /// not be made current.
+    pub fn acquire(self) -> Result<C,Error> {
+        try!(self.context.try_make_current());
+        Ok(self.context)
+    }
+}
+
+#[unsafe_destructor]
+impl<'a,N,C:GraphicsContextMethods<N>> Drop for CurrentGuard<'a,C> {
+    fn drop(&mut self) {
+        let result = match self.previous {
+            Some(ref previous) => previous.try_make_current(),
+            None => self.context.try_release_current(),
+        };
+        match result {
+            Ok(()) => {}
+            Err(error) => debug!("failed to restore the previously current context: {}", error),
+        }
+    }
 }
 
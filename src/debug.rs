@@ -0,0 +1,244 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Routing of `KHR_debug` driver messages into the `log` crate.
+//!
+//! Create the context with `ContextAttributes::debug` set so that the driver reports as much as
+//! it can, then call `install()`. Messages at or above the chosen severity are logged; tests can
+//! additionally capture them with `collect_messages()`.
+
+use context::GraphicsContextMethods;
+use error::{Error, ExtensionUnsupported};
+
+use libc::{c_char, c_void};
+use opengles::gl2::{EXTENSIONS, GLenum, GLint, GLsizei, GLubyte, GLuint, VERSION};
+use opengles::gl2;
+use std::c_str::CString;
+use std::cell::RefCell;
+use std::from_str::from_str;
+use std::mem;
+use std::ptr;
+use std::string;
+
+static GL_DEBUG_OUTPUT_SYNCHRONOUS: GLenum = 0x8242;
+static GL_DEBUG_SEVERITY_NOTIFICATION: GLenum = 0x826b;
+static GL_DEBUG_SEVERITY_HIGH: GLenum = 0x9146;
+static GL_DEBUG_SEVERITY_MEDIUM: GLenum = 0x9147;
+static GL_DEBUG_SEVERITY_LOW: GLenum = 0x9148;
+static GL_DEBUG_OUTPUT: GLenum = 0x92e0;
+static GL_NUM_EXTENSIONS: GLenum = 0x821d;
+
+type GLDebugProc = extern "C" fn(source: GLenum,
+                                 type_: GLenum,
+                                 id: GLuint,
+                                 severity: GLenum,
+                                 length: GLsizei,
+                                 message: *const c_char,
+                                 userParam: *const c_void);
+type GlDebugMessageCallback = extern "C" fn(callback: GLDebugProc, userParam: *const c_void);
+type GlGetIntegerv = extern "C" fn(pname: GLenum, data: *mut GLint);
+type GlGetStringi = extern "C" fn(name: GLenum, index: GLuint) -> *const GLubyte;
+
+/// How serious a debug message is, from least to most.
+#[deriving(Clone, PartialEq, PartialOrd, Show)]
+pub enum Severity {
+    /// Informational messages, logged with `debug!`.
+    NotificationSeverity,
+    /// Minor performance warnings and the like, logged with `info!`.
+    LowSeverity,
+    /// Major performance warnings and use of deprecated functionality, logged with `warn!`.
+    MediumSeverity,
+    /// Errors and undefined behavior, logged with `error!`.
+    HighSeverity,
+}
+
+impl Severity {
+    fn from_gl(severity: GLenum) -> Severity {
+        match severity {
+            GL_DEBUG_SEVERITY_HIGH => HighSeverity,
+            GL_DEBUG_SEVERITY_MEDIUM => MediumSeverity,
+            GL_DEBUG_SEVERITY_LOW => LowSeverity,
+            _ => NotificationSeverity,
+        }
+    }
+}
+
+/// A message reported by the driver.
+#[deriving(Clone, PartialEq, Show)]
+pub struct DebugMessage {
+    /// The `GL_DEBUG_SOURCE_*` value naming the part of the system that sent the message.
+    pub source: GLenum,
+    /// The `GL_DEBUG_TYPE_*` value classifying the message.
+    pub message_type: GLenum,
+    /// The driver-specific ID of the message.
+    pub id: GLuint,
+    /// How serious the message is.
+    pub severity: Severity,
+    /// The text of the message.
+    pub message: String,
+}
+
+// The messages captured by `collect_messages()` on this task, if it is collecting.
+local_data_key!(collected_messages: RefCell<Vec<DebugMessage>>)
+
+/// Makes the given context current and installs a debug message callback on it that logs every
+/// message at or above `min_severity`. Output is made synchronous, so messages are reported on
+/// the task that made the offending call.
+///
+/// Returns `ExtensionUnsupported` if the context does not implement `KHR_debug` or OpenGL 4.3.
+pub fn install<N,C:GraphicsContextMethods<N>>(context: &C, min_severity: Severity)
+                                              -> Result<(),Error> {
+    try!(context.try_make_current());
+
+    // GLX and Mesa return stubs for functions they do not implement, so the entry point existing
+    // proves nothing.
+    if !supports_debug_output(context) {
+        return Err(ExtensionUnsupported("GL_KHR_debug"))
+    }
+
+    // OpenGL ES only has the suffixed entry point before version 3.2.
+    let address = match context.get_proc_address("glDebugMessageCallback") {
+        Some(address) => address,
+        None => match context.get_proc_address("glDebugMessageCallbackKHR") {
+            Some(address) => address,
+            None => return Err(ExtensionUnsupported("GL_KHR_debug")),
+        },
+    };
+
+    unsafe {
+        let debug_message_callback: GlDebugMessageCallback = mem::transmute(address);
+        // The filter travels as the user parameter, so no state has to outlive the context.
+        debug_message_callback(debug_callback, min_severity as uint as *const c_void);
+    }
+    gl2::enable(GL_DEBUG_OUTPUT);
+    gl2::enable(GL_DEBUG_OUTPUT_SYNCHRONOUS);
+    Ok(())
+}
+
+// Returns true if the current context has debug output, either as part of OpenGL 4.3 or OpenGL ES
+// 3.2 or through `GL_KHR_debug`.
+fn supports_debug_output<N,C:GraphicsContextMethods<N>>(context: &C) -> bool {
+    let version = gl2::get_string(VERSION);
+    match parse_version(version.as_slice()) {
+        Some((true, version)) if version >= (3, 2) => return true,
+        Some((false, version)) if version >= (4, 3) => return true,
+        _ => {}
+    }
+
+    // Core profiles only list their extensions through `glGetStringi`.
+    let extensions = gl2::get_string(EXTENSIONS);
+    if !extensions.is_empty() {
+        return extensions.as_slice().split(' ').any(|extension| extension == "GL_KHR_debug")
+    }
+    let (get_integer_v, get_string_i): (GlGetIntegerv, GlGetStringi) =
+        match (context.get_proc_address("glGetIntegerv"),
+               context.get_proc_address("glGetStringi")) {
+            (Some(get_integer_v), Some(get_string_i)) => unsafe {
+                (mem::transmute(get_integer_v), mem::transmute(get_string_i))
+            },
+            _ => return false,
+        };
+    let mut count = 0;
+    get_integer_v(GL_NUM_EXTENSIONS, &mut count);
+    range(0, count as GLuint).any(|index| {
+        let extension = get_string_i(EXTENSIONS, index);
+        if extension == ptr::null() {
+            return false
+        }
+        let extension = unsafe { CString::new(extension as *const c_char, false) };
+        extension.as_str() == Some("GL_KHR_debug")
+    })
+}
+
+// Parses a `GL_VERSION` string such as "4.5 (Core Profile) Mesa 20.0" or "OpenGL ES 3.2 Mesa",
+// returning whether it names OpenGL ES along with the major and minor version.
+fn parse_version(version: &str) -> Option<(bool, (uint, uint))> {
+    let (es, version) = if version.starts_with("OpenGL ES ") {
+        (true, version.slice_from("OpenGL ES ".len()))
+    } else {
+        (false, version)
+    };
+    let number = match version.split(' ').next() {
+        Some(number) => number,
+        None => return None,
+    };
+    let mut parts = number.split('.');
+    match (parts.next().and_then(from_str), parts.next().and_then(from_str)) {
+        (Some(major), Some(minor)) => Some((es, (major, minor))),
+        _ => None,
+    }
+}
+
+/// Runs `f` and returns its result together with the debug messages that were reported on this
+/// task while it ran. Messages are still logged as usual.
+pub fn collect_messages<T>(f: || -> T) -> (T, Vec<DebugMessage>) {
+    let previous = collected_messages.replace(Some(RefCell::new(Vec::new())));
+    let result = f();
+    let messages = collected_messages.replace(previous).unwrap();
+    (result, messages.unwrap())
+}
+
+extern "C" fn debug_callback(source: GLenum,
+                             message_type: GLenum,
+                             id: GLuint,
+                             severity: GLenum,
+                             length: GLsizei,
+                             message: *const c_char,
+                             user_param: *const c_void) {
+    let severity = Severity::from_gl(severity);
+    if (severity as uint) < (user_param as uint) {
+        return
+    }
+
+    let message = unsafe {
+        string::raw::from_buf_len(message as *const u8, length as uint)
+    };
+    match severity {
+        HighSeverity => error!("GL error 0x{:x}: {}", id, message),
+        MediumSeverity => warn!("GL warning 0x{:x}: {}", id, message),
+        LowSeverity => info!("GL message 0x{:x}: {}", id, message),
+        NotificationSeverity => debug!("GL message 0x{:x}: {}", id, message),
+    }
+
+    match collected_messages.get() {
+        Some(messages) => {
+            messages.borrow_mut().push(DebugMessage {
+                source: source,
+                message_type: message_type,
+                id: id,
+                severity: severity,
+                message: message,
+            })
+        }
+        None => {}
+    }
+}
+
+#[test]
+fn messages_below_the_minimum_severity_are_dropped() {
+    let text = "message";
+    let ((), messages) = collect_messages(|| {
+        let min_severity = MediumSeverity as uint as *const c_void;
+        for &severity in [ GL_DEBUG_SEVERITY_LOW, GL_DEBUG_SEVERITY_HIGH ].iter() {
+            debug_callback(0, 0, 1, severity, text.len() as GLsizei,
+                           text.as_ptr() as *const c_char, min_severity);
+        }
+    });
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].severity, HighSeverity);
+    assert_eq!(messages[0].message.as_slice(), text);
+}
+
+#[test]
+fn versions_are_parsed_from_gl_version_strings() {
+    assert_eq!(parse_version("4.5 (Core Profile) Mesa 20.0.8"), Some((false, (4, 5))));
+    assert_eq!(parse_version("3.1 Mesa 20.0.8"), Some((false, (3, 1))));
+    assert_eq!(parse_version("OpenGL ES 3.2 Mesa 20.0.8"), Some((true, (3, 2))));
+    assert_eq!(parse_version("garbage"), None);
+}
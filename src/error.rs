@@ -0,0 +1,63 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Errors that can occur while creating or using graphics contexts.
+
+use opengles::gl2::GLenum;
+use std::fmt;
+
+/// The ways in which creating or using a graphics context can fail.
+#[deriving(Clone, PartialEq)]
+pub enum Error {
+    /// The connection to the windowing system could not be opened.
+    NoDisplay,
+    /// No visual or pixel format matches the requested attributes.
+    NoMatchingVisual,
+    /// The native graphics context could not be created.
+    ContextCreationFailed,
+    /// The context could not be made current.
+    MakeCurrentFailed,
+    /// The surface backing the context could not be created or bound.
+    SurfaceCreationFailed,
+    /// The framebuffer is incomplete. Carries the status returned by `glCheckFramebufferStatus`.
+    FramebufferIncomplete(GLenum),
+    /// The platform lacks an extension or API version that is required. Carries its name.
+    ExtensionUnsupported(&'static str),
+    /// Every other buffer of a swap chain is still held by the consumer.
+    NoFreeBuffer,
+    /// A swap chain buffer was acquired before the previously acquired one was released.
+    BufferNotReleased,
+    /// Waiting for a frame to complete failed.
+    SyncFailed,
+    /// The frame did not complete in time, typically because the producer has gone away.
+    SyncTimedOut,
+    /// The surface ID was produced by a different backend than the one opening it.
+    IncompatibleSurface,
+}
+
+impl fmt::Show for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NoDisplay => write!(f, "could not open a connection to the display"),
+            NoMatchingVisual => write!(f, "no visual matches the requested attributes"),
+            ContextCreationFailed => write!(f, "could not create the graphics context"),
+            MakeCurrentFailed => write!(f, "could not make the graphics context current"),
+            SurfaceCreationFailed => write!(f, "could not create the shared surface"),
+            FramebufferIncomplete(status) => {
+                write!(f, "framebuffer incomplete (status 0x{:x})", status)
+            }
+            ExtensionUnsupported(name) => write!(f, "{} is not supported", name),
+            NoFreeBuffer => write!(f, "every swap chain buffer is held by the consumer"),
+            BufferNotReleased => write!(f, "the previously acquired buffer was not released"),
+            SyncFailed => write!(f, "could not wait for the frame to complete"),
+            SyncTimedOut => write!(f, "timed out waiting for the frame to complete"),
+            IncompatibleSurface => write!(f, "the surface ID belongs to a different backend"),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Ownership of the file descriptors behind fences and dma-bufs.
+
+use libc::{c_int, close};
+
+/// A file descriptor that is closed when it goes out of scope. It cannot be cloned, so it is closed
+/// exactly once; to send it to another process over `SCM_RIGHTS`, take it out with
+/// `into_raw_fd()` and close it once it has been sent.
+#[deriving(PartialEq, Show)]
+pub struct FileDescriptor {
+    fd: c_int,
+}
+
+impl FileDescriptor {
+    /// Takes ownership of the given file descriptor, such as one received over a Unix socket.
+    pub fn from_raw_fd(fd: int) -> FileDescriptor {
+        FileDescriptor {
+            fd: fd as c_int,
+        }
+    }
+
+    /// Returns the file descriptor without giving up ownership.
+    pub fn as_raw_fd(&self) -> int {
+        self.fd as int
+    }
+
+    /// Gives up ownership of the file descriptor, which the caller must close.
+    pub fn into_raw_fd(self) -> int {
+        let fd = self.fd;
+        let mut this = self;
+        this.fd = -1;
+        fd as int
+    }
+}
+
+impl Drop for FileDescriptor {
+    fn drop(&mut self) {
+        if self.fd >= 0 {
+            unsafe {
+                close(self.fd);
+            }
+        }
+    }
+}
+
+#[test]
+fn descriptors_are_closed_when_dropped_unless_released() {
+    use libc::{c_void, pipe, read, size_t, write};
+
+    let mut fds = [ 0 as c_int, 0 ];
+    let mut buffer = [ 0u8, ..2 ];
+    unsafe {
+        assert_eq!(pipe(fds.as_mut_ptr()), 0);
+        let reader = FileDescriptor::from_raw_fd(fds[0] as int);
+
+        // A released descriptor stays open.
+        let raw = FileDescriptor::from_raw_fd(fds[1] as int).into_raw_fd();
+        assert_eq!(write(raw as c_int, buffer.as_ptr() as *const c_void, 1), 1);
+
+        // Once the write end is dropped, the reader sees the end of the pipe.
+        drop(FileDescriptor::from_raw_fd(raw));
+        let reader_fd = reader.as_raw_fd() as c_int;
+        assert_eq!(read(reader_fd, buffer.as_mut_ptr() as *mut c_void, 2 as size_t), 1);
+        assert_eq!(read(reader_fd, buffer.as_mut_ptr() as *mut c_void, 2 as size_t), 0);
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Tokens that tell a consumer when a flushed frame is complete.
+
+use base::ShmRegionId;
+use error::{Error, ExtensionUnsupported, SyncFailed, SyncTimedOut};
+use fd::FileDescriptor;
+
+use libc::{EINTR, c_int, c_short};
+use std::os;
+use std::time::Duration;
+
+/// How long `SyncToken::wait()` waits for a frame before assuming that the producer has gone away.
+pub static DEFAULT_SYNC_TIMEOUT_MS: i64 = 10000;
+
+/// Returned by `ShareContext::flush()`. The consumer must wait on the token before reading the
+/// frame it was returned for.
+///
+/// A token may own a file descriptor, so it cannot be cloned or serialized as a whole. Send the
+/// fence of a `NativeFenceSync` over a Unix socket, and everything else as plain values.
+#[deriving(PartialEq, Show)]
+pub enum SyncToken {
+    /// The producer already waited for rendering to finish, so there is nothing to wait for. This
+    /// is the fallback on platforms without cross-process fences.
+    FinishedSync,
+    /// The producer submitted its commands, and the platform orders the consumer's reads after
+    /// them, as it does for IOSurfaces.
+    FlushedSync,
+    /// A native fence from `EGL_ANDROID_native_fence_sync`, which becomes readable once rendering
+    /// has finished. The token closes the fence when it is dropped; take it out with
+    /// `into_raw_fd()` to send it to the consumer over `SCM_RIGHTS`, and wrap the received
+    /// descriptor in a new token there.
+    NativeFenceSync(FileDescriptor),
+    /// A frame counter in the given shared-memory region, which reaches the given value once the
+    /// frame is complete.
+    CounterSync(ShmRegionId, uint),
+}
+
+impl SyncToken {
+    /// Blocks until the frame this token was returned for is complete, then releases whatever the
+    /// token holds. Returns the reason if waiting failed, which is `SyncTimedOut` if the frame did
+    /// not complete within `DEFAULT_SYNC_TIMEOUT_MS`.
+    pub fn wait(self) -> Result<(),Error> {
+        self.wait_timeout(Duration::milliseconds(DEFAULT_SYNC_TIMEOUT_MS))
+    }
+
+    /// Blocks until the frame this token was returned for is complete or the timeout has passed.
+    pub fn wait_timeout(self, timeout: Duration) -> Result<(),Error> {
+        match self {
+            FinishedSync | FlushedSync => Ok(()),
+            NativeFenceSync(fence) => wait_for_fd(fence.as_raw_fd() as c_int, timeout),
+            CounterSync(id, value) => wait_for_counter(id, value, timeout),
+        }
+    }
+}
+
+#[repr(C)]
+struct PollFd {
+    fd: c_int,
+    events: c_short,
+    revents: c_short,
+}
+
+#[cfg(target_os="linux")]
+#[allow(non_camel_case_types)]
+type nfds_t = ::libc::c_ulong;
+#[cfg(not(target_os="linux"))]
+#[allow(non_camel_case_types)]
+type nfds_t = ::libc::c_uint;
+
+static POLLIN: c_short = 0x1;
+
+extern {
+    fn poll(fds: *mut PollFd, nfds: nfds_t, timeout: c_int) -> c_int;
+}
+
+fn wait_for_fd(fd: c_int, timeout: Duration) -> Result<(),Error> {
+    let mut poll_fd = PollFd {
+        fd: fd,
+        events: POLLIN,
+        revents: 0,
+    };
+    loop {
+        match unsafe { poll(&mut poll_fd, 1, timeout.num_milliseconds() as c_int) } {
+            0 => return Err(SyncTimedOut),
+            result if result > 0 => return Ok(()),
+            _ => {}
+        }
+        // Signals interrupt the wait without the fence having signaled.
+        if os::errno() as c_int != EINTR {
+            debug!("poll failed on fence {}: {}", fd, os::last_os_error());
+            return Err(SyncFailed)
+        }
+    }
+}
+
+#[cfg(target_os="linux")]
+fn wait_for_counter(id: ShmRegionId, value: uint, timeout: Duration) -> Result<(),Error> {
+    use shm::FrameCounter;
+    FrameCounter::wait(&id, value, timeout)
+}
+
+#[cfg(not(target_os="linux"))]
+fn wait_for_counter(_: ShmRegionId, _: uint, _: Duration) -> Result<(),Error> {
+    Err(ExtensionUnsupported("shared-memory frame counters"))
+}
@@ -10,7 +10,7 @@
 #![crate_name = "sharegl"]
 #![crate_type = "rlib"]
 
-#![feature(phase)]
+#![feature(phase, unsafe_destructor)]
 
 #![allow(non_snake_case_functions)]
 
@@ -19,28 +19,76 @@ extern crate geom;
 extern crate libc;
 #[phase(plugin, link)]
 extern crate log;
+extern crate serialize;
 extern crate std;
 extern crate sync;
 
-#[cfg(target_os="macos")]
+#[cfg(all(target_os="macos", feature="iosurface"))]
 extern crate core_foundation;
-#[cfg(target_os="macos")]
+#[cfg(all(target_os="macos", feature="iosurface"))]
 extern crate io_surface;
-#[cfg(target_os="macos")]
 extern crate opengles;
 
+pub use attributes::{Api, ContextAttributes, PixelFormat, Profile};
+pub use base::{DmaBuf, DmaBufPlane, ShmRegionId, SurfaceDescriptor, SurfaceFormat, SurfaceId};
+pub use error::Error;
+pub use fd::FileDescriptor;
+pub use fence::SyncToken;
+pub use share_group::{GroupContext, ShareGroup};
+pub use swap_chain::{Frame, SwapChain, SwapChainReader};
+
+pub mod attributes;
 pub mod base;
 pub mod context;
+pub mod debug;
+pub mod error;
+pub mod fd;
+pub mod fence;
+pub mod registry;
+pub mod resources;
+pub mod share_group;
+pub mod swap_chain;
+
+// Copying frames through shared memory, which works with every Linux backend.
+#[cfg(target_os="linux")]
+pub mod readback;
+
+#[cfg(target_os="linux")]
+mod shm;
+
+// Dma-bufs in ordinary memory, for testing dma-buf import without a GPU.
+#[cfg(all(test, target_os="linux"))]
+mod udmabuf;
 
-#[cfg(target_os="macos")]
+// Each backend is built only when its feature is enabled. `platform` is the native backend: GLX on
+// Linux, IOSurface on Mac, and the dummy backend everywhere else.
+
+#[cfg(all(target_os="macos", feature="iosurface"))]
 #[path="platform/macos.rs"]
 pub mod platform;
 
-#[cfg(target_os="linux")]
+#[cfg(all(target_os="linux", feature="glx"))]
 #[path="platform/linux.rs"]
 pub mod platform;
 
-#[cfg(target_os="windows")]
-#[cfg(target_os="android")]
+// The headless EGL backend, for Linux machines without an X server.
+#[cfg(all(target_os="linux", feature="egl"))]
+#[path="platform/egl.rs"]
+pub mod egl;
+
+// The pure-software OSMesa backend, for deterministic rendering tests.
+#[cfg(all(target_os="linux", feature="osmesa"))]
+#[path="platform/osmesa.rs"]
+pub mod osmesa;
+
+// The dummy backend can be selected explicitly in tests, and is the platform when no native
+// backend is built.
+#[cfg(any(feature="dummy",
+          not(any(all(target_os="macos", feature="iosurface"),
+                  all(target_os="linux", feature="glx")))))]
 #[path="platform/dummy.rs"]
-pub mod platform;
+pub mod dummy;
+
+#[cfg(not(any(all(target_os="macos", feature="iosurface"),
+              all(target_os="linux", feature="glx"))))]
+pub use dummy as platform;
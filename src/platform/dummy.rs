@@ -7,26 +7,243 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+//! A backend that creates no native objects at all. It is the platform on operating systems with
+//! no native backend, and is always available as `sharegl::dummy` so that code built on this crate
+//! can be tested without a windowing system.
+//!
+//! Contexts and surfaces are only bookkeeping: GL calls made while a dummy context is current go
+//! nowhere.
+
+use attributes::{ContextAttributes, PixelFormat};
+use base::{DummyId, RGBA8Format, ShareContext, SurfaceFormat, SurfaceId};
+use context::GraphicsContextMethods;
+use error::Error;
+use fence::{FinishedSync, SyncToken};
+
 use geom::size::Size2D;
-use base::ShareContext;
+use libc::c_void;
+use std::kinds::marker;
+use std::sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+use sync::{Arc, Weak};
+
+// The source of context and surface IDs. Zero is never handed out.
+static mut next_id: AtomicUint = INIT_ATOMIC_UINT;
 
-pub type Context = DummyContext;
+fn new_id() -> uint {
+    unsafe {
+        next_id.fetch_add(1, SeqCst) + 1
+    }
+}
 
-struct DummyContext {
-    _unused: int
+// The native context that is current on this task.
+local_data_key!(current_context: Weak<NativeContext>)
+
+/// The stand-in for a native graphics context.
+pub struct NativeContext {
+    id: uint,
+    pixel_format: PixelFormat,
+}
+
+impl NativeContext {
+    /// Returns an ID that is unique among all dummy contexts in this process.
+    pub fn id(&self) -> uint {
+        self.id
+    }
 }
 
-impl ShareContext for DummyContext {
-    fn new(_size: Size2D<int>) -> DummyContext {
-        DummyContext {
-            _unused: 0
+/// Dummy interface to 3D graphics contexts.
+pub struct GraphicsContext {
+    native: Arc<NativeContext>,
+    // A context can be sent to another thread but not used from two threads at once.
+    _no_sync: marker::NoSync,
+}
+
+impl GraphicsContextMethods<NativeContext> for GraphicsContext {
+    fn wrap(instance: Arc<NativeContext>) -> GraphicsContext {
+        GraphicsContext {
+            native: instance,
+            _no_sync: marker::NoSync,
+        }
+    }
+
+    fn native(&self) -> Arc<NativeContext> {
+        self.native.clone()
+    }
+
+    fn try_new_with_attributes(attributes: &ContextAttributes) -> Result<GraphicsContext,Error> {
+        Ok(GraphicsContextMethods::wrap(Arc::new(NativeContext {
+            id: new_id(),
+            pixel_format: attributes.pixel_format.clone(),
+        })))
+    }
+
+    fn try_new_shared_with_attributes(&self, attributes: &ContextAttributes)
+                                      -> Result<GraphicsContext,Error> {
+        GraphicsContextMethods::try_new_with_attributes(attributes)
+    }
+
+    fn try_make_current(&self) -> Result<(),Error> {
+        current_context.replace(Some(self.native.downgrade()));
+        Ok(())
+    }
+
+    fn try_release_current(&self) -> Result<(),Error> {
+        if self.is_current() {
+            current_context.replace(None);
         }
+        Ok(())
     }
 
-    fn flush(&self) {
+    fn is_current(&self) -> bool {
+        match current_context.get().and_then(|native| native.upgrade()) {
+            Some(native) => native.id == self.native.id,
+            None => false,
+        }
+    }
+
+    fn current() -> Option<GraphicsContext> {
+        current_context.get().and_then(|native| native.upgrade()).map(|native| {
+            GraphicsContextMethods::wrap(native)
+        })
     }
 
-    fn id(&self) -> int {
-        0
+    /// Returns the requested pixel format, which the dummy backend always grants.
+    fn pixel_format(&self) -> PixelFormat {
+        self.native.pixel_format.clone()
     }
+
+    /// The dummy backend implements no GL functions.
+    fn get_proc_address(&self, _: &str) -> Option<*const c_void> {
+        None
+    }
+}
+
+impl Clone for GraphicsContext {
+    fn clone(&self) -> GraphicsContext {
+        GraphicsContextMethods::wrap(self.native())
+    }
+}
+
+/// A share context that renders nowhere. Each context has a distinct, nonzero ID.
+pub struct Context {
+    context: GraphicsContext,
+    id: uint,
+    generation: uint,
+    size: Size2D<int>,
+}
+
+impl Context {
+    /// Returns the graphics context this share context renders with.
+    pub fn graphics_context<'a>(&'a self) -> &'a GraphicsContext {
+        &self.context
+    }
+}
+
+impl ShareContext for Context {
+    fn try_new(size: Size2D<int>) -> Result<Context,Error> {
+        let context: GraphicsContext = try!(GraphicsContextMethods::try_new());
+        try!(context.try_make_current());
+        Ok(Context {
+            context: context,
+            id: new_id(),
+            generation: 0,
+            size: size,
+        })
+    }
+
+    /// Stands in for a new surface by handing out a new ID.
+    fn try_resize(&mut self, size: Size2D<int>) -> Result<(),Error> {
+        self.id = new_id();
+        self.generation += 1;
+        self.size = size;
+        Ok(())
+    }
+
+    fn flush(&self) -> SyncToken {
+        FinishedSync
+    }
+
+    fn id(&self) -> SurfaceId {
+        DummyId(self.id)
+    }
+
+    fn generation(&self) -> uint {
+        self.generation
+    }
+
+    fn size(&self) -> Size2D<int> {
+        self.size
+    }
+
+    fn format(&self) -> SurfaceFormat {
+        RGBA8Format
+    }
+}
+
+#[test]
+fn make_current_tracks_the_current_context() {
+    let first: GraphicsContext = GraphicsContextMethods::new();
+    let second: GraphicsContext = first.new_shared();
+    first.make_current();
+    assert!(first.is_current() && !second.is_current());
+    second.make_current();
+    assert!(second.is_current() && !first.is_current());
+}
+
+#[test]
+fn current_guard_restores_the_previous_context() {
+    use context::CurrentGuard;
+
+    let first: GraphicsContext = GraphicsContextMethods::new();
+    let second: GraphicsContext = GraphicsContextMethods::new();
+    first.make_current();
+    {
+        let _guard = CurrentGuard::new(&second).unwrap();
+        assert!(second.is_current());
+    }
+    assert!(first.is_current());
+
+    first.release_current();
+    assert!(GraphicsContextMethods::current().map(|_: GraphicsContext| ()).is_none());
+    {
+        let _guard = CurrentGuard::new(&second).unwrap();
+    }
+    assert!(!first.is_current() && !second.is_current());
+}
+
+#[test]
+fn contexts_can_be_handed_off_to_other_tasks() {
+    use context::ContextHandoff;
+
+    let context: GraphicsContext = GraphicsContextMethods::new();
+    context.make_current();
+    let handoff = ContextHandoff::new(context).ok().unwrap();
+    assert!(current_context.get().and_then(|native| native.upgrade()).is_none());
+
+    let (sender, receiver) = channel();
+    spawn(proc() {
+        let context = handoff.acquire().unwrap();
+        sender.send(context.is_current());
+    });
+    assert!(receiver.recv());
+}
+
+#[test]
+fn share_contexts_have_distinct_ids() {
+    let first: Context = ShareContext::new(Size2D(10, 10));
+    let second: Context = ShareContext::new(Size2D(10, 10));
+    assert!(first.id() != DummyId(0));
+    assert!(first.id() != second.id());
+    assert!(second.graphics_context().is_current());
+}
+
+#[test]
+fn requested_pixel_format_is_granted() {
+    use std::default::Default;
+
+    let mut attributes: ContextAttributes = Default::default();
+    attributes.pixel_format.stencil_bits = 8;
+    attributes.pixel_format.samples = 4;
+    let context: GraphicsContext = GraphicsContextMethods::new_with_attributes(&attributes);
+    assert_eq!(context.pixel_format(), attributes.pixel_format);
 }
@@ -0,0 +1,1511 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A headless backend built on EGL, for machines without an X server.
+//!
+//! Contexts are created on the Mesa surfaceless platform or on an EGL device when the driver
+//! supports them, falling back to the default EGL display otherwise. Contexts render without a
+//! surface where `EGL_KHR_surfaceless_context` is available, and into a pbuffer where it is not.
+//! This works with Mesa's software rasterizer on machines without a GPU.
+//!
+//! Share contexts are published to other processes as dma-bufs where the driver implements
+//! `EGL_MESA_image_dma_buf_export`, and `SurfaceReader` imports them through
+//! `EGL_EXT_image_dma_buf_import`. Where it does not, frames are copied through shared memory.
+
+use attributes::{ContextAttributes, OpenGL, OpenGLES, PixelFormat};
+use base::{DRM_FORMAT_MOD_INVALID, DmaBuf, DmaBufId, DmaBufPlane, RGBA8Format, ShareContext};
+use base::{SharedSurfaceReader, ShmId, SurfaceFormat, SurfaceId};
+use context::{CurrentContext, GraphicsContextMethods};
+use error::{ContextCreationFailed, Error, FramebufferIncomplete, MakeCurrentFailed};
+use error::{ExtensionUnsupported, IncompatibleSurface, NoDisplay, NoMatchingVisual};
+use error::SurfaceCreationFailed;
+use fd::FileDescriptor;
+use fence::{FinishedSync, NativeFenceSync, SyncToken};
+use readback::{ShmSurface, ShmSurfaceReader};
+use resources::{ContextResource, DisplayResource, DrawableResource, FramebufferResource};
+use resources::{TextureResource, TrackedResource};
+
+use geom::size::Size2D;
+use libc::{c_char, c_int, c_uint, c_void, int32_t};
+use opengles::gl2::{COLOR_ATTACHMENT0, FRAMEBUFFER, FRAMEBUFFER_COMPLETE, GLenum, GLint};
+use opengles::gl2::{GLsizei, GLuint, LINEAR, NEAREST, RGBA, TEXTURE_2D, TEXTURE_MAG_FILTER};
+use opengles::gl2::{TEXTURE_MIN_FILTER, UNSIGNED_BYTE};
+use opengles::gl2;
+use std::c_str::{CString, ToCStr};
+use std::default::Default;
+use std::kinds::marker;
+use std::mem;
+use std::ptr;
+use std::sync::one::{Once, ONCE_INIT};
+use sync::{Arc, Mutex, Weak};
+
+// Types.
+
+type EGLBoolean = c_uint;
+type EGLenum = c_uint;
+type EGLint = int32_t;
+type EGLConfig = *mut c_void;
+pub type EGLContext = *mut c_void;
+type EGLDeviceEXT = *mut c_void;
+pub type EGLDisplay = *mut c_void;
+type EGLSurface = *mut c_void;
+type EGLSyncKHR = *mut c_void;
+type EGLImageKHR = *mut c_void;
+type EGLClientBuffer = *mut c_void;
+type EGLuint64KHR = u64;
+
+type EglGetPlatformDisplayEXT = extern "C" fn(platform: EGLenum,
+                                              native_display: *mut c_void,
+                                              attrib_list: *const EGLint)
+                                              -> EGLDisplay;
+type EglQueryDevicesEXT = extern "C" fn(max_devices: EGLint,
+                                        devices: *mut EGLDeviceEXT,
+                                        num_devices: *mut EGLint)
+                                        -> EGLBoolean;
+type EglCreateSyncKHR = extern "C" fn(dpy: EGLDisplay,
+                                      type_: EGLenum,
+                                      attrib_list: *const EGLint)
+                                      -> EGLSyncKHR;
+type EglDestroySyncKHR = extern "C" fn(dpy: EGLDisplay, sync: EGLSyncKHR) -> EGLBoolean;
+type EglDupNativeFenceFDANDROID = extern "C" fn(dpy: EGLDisplay, sync: EGLSyncKHR) -> EGLint;
+type EglCreateImageKHR = extern "C" fn(dpy: EGLDisplay,
+                                       ctx: EGLContext,
+                                       target: EGLenum,
+                                       buffer: EGLClientBuffer,
+                                       attrib_list: *const EGLint)
+                                       -> EGLImageKHR;
+type EglDestroyImageKHR = extern "C" fn(dpy: EGLDisplay, image: EGLImageKHR) -> EGLBoolean;
+type EglExportDMABUFImageQueryMESA = extern "C" fn(dpy: EGLDisplay,
+                                                   image: EGLImageKHR,
+                                                   fourcc: *mut c_int,
+                                                   num_planes: *mut c_int,
+                                                   modifiers: *mut EGLuint64KHR)
+                                                   -> EGLBoolean;
+type EglExportDMABUFImageMESA = extern "C" fn(dpy: EGLDisplay,
+                                              image: EGLImageKHR,
+                                              fds: *mut c_int,
+                                              strides: *mut EGLint,
+                                              offsets: *mut EGLint)
+                                              -> EGLBoolean;
+type GlEGLImageTargetTexture2DOES = extern "C" fn(target: GLenum, image: EGLImageKHR);
+
+// Constants.
+
+static EGL_ALPHA_SIZE: EGLint = 0x3021;
+static EGL_BLUE_SIZE: EGLint = 0x3022;
+static EGL_GREEN_SIZE: EGLint = 0x3023;
+static EGL_RED_SIZE: EGLint = 0x3024;
+static EGL_DEPTH_SIZE: EGLint = 0x3025;
+static EGL_STENCIL_SIZE: EGLint = 0x3026;
+static EGL_CONFIG_ID: EGLint = 0x3028;
+static EGL_SAMPLES: EGLint = 0x3031;
+static EGL_SAMPLE_BUFFERS: EGLint = 0x3032;
+static EGL_SURFACE_TYPE: EGLint = 0x3033;
+static EGL_NONE: EGLint = 0x3038;
+static EGL_RENDERABLE_TYPE: EGLint = 0x3040;
+static EGL_HEIGHT: EGLint = 0x3056;
+static EGL_WIDTH: EGLint = 0x3057;
+
+static EGL_PBUFFER_BIT: EGLint = 0x0001;
+static EGL_OPENGL_ES2_BIT: EGLint = 0x0004;
+static EGL_OPENGL_BIT: EGLint = 0x0008;
+static EGL_OPENGL_ES3_BIT_KHR: EGLint = 0x0040;
+
+static EGL_CONTEXT_MAJOR_VERSION_KHR: EGLint = 0x3098;
+static EGL_CONTEXT_MINOR_VERSION_KHR: EGLint = 0x30fb;
+static EGL_CONTEXT_FLAGS_KHR: EGLint = 0x30fc;
+static EGL_CONTEXT_OPENGL_PROFILE_MASK_KHR: EGLint = 0x30fd;
+
+static EGL_CONTEXT_OPENGL_DEBUG_BIT_KHR: EGLint = 0x0001;
+static EGL_CONTEXT_OPENGL_FORWARD_COMPATIBLE_BIT_KHR: EGLint = 0x0002;
+static EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT_KHR: EGLint = 0x0001;
+static EGL_CONTEXT_OPENGL_COMPATIBILITY_PROFILE_BIT_KHR: EGLint = 0x0002;
+
+static EGL_EXTENSIONS: EGLint = 0x3055;
+static EGL_DRAW: EGLint = 0x3059;
+static EGL_READ: EGLint = 0x305a;
+static EGL_GL_COLORSPACE_KHR: EGLint = 0x309d;
+static EGL_GL_COLORSPACE_SRGB_KHR: EGLint = 0x3089;
+static EGL_OPENGL_ES_API: EGLenum = 0x30a0;
+static EGL_OPENGL_API: EGLenum = 0x30a2;
+
+static EGL_PLATFORM_DEVICE_EXT: EGLenum = 0x313f;
+static EGL_PLATFORM_SURFACELESS_MESA: EGLenum = 0x31dd;
+
+static EGL_SYNC_NATIVE_FENCE_ANDROID: EGLenum = 0x3144;
+static EGL_NO_NATIVE_FENCE_FD_ANDROID: EGLint = -1;
+
+static EGL_GL_TEXTURE_2D_KHR: EGLenum = 0x30b1;
+static EGL_LINUX_DMA_BUF_EXT: EGLenum = 0x3270;
+static EGL_LINUX_DRM_FOURCC_EXT: EGLint = 0x3271;
+
+// The most planes a dma-buf import can describe.
+static MAX_DMA_BUF_PLANES: uint = 4;
+
+// The fd, offset, pitch, modifier low and modifier high attributes of each plane.
+static EGL_DMA_BUF_PLANE_ATTRIBUTES: [[EGLint, ..5], ..4] = [
+    [ 0x3272, 0x3273, 0x3274, 0x3443, 0x3444 ],
+    [ 0x3275, 0x3276, 0x3277, 0x3445, 0x3446 ],
+    [ 0x3278, 0x3279, 0x327a, 0x3447, 0x3448 ],
+    [ 0x3440, 0x3441, 0x3442, 0x3449, 0x344a ],
+];
+
+// External bindings to EGL.
+
+#[link(name = "EGL")]
+extern {
+    fn eglGetProcAddress(procname: *const c_char) -> *const c_void;
+    fn eglGetError() -> EGLint;
+    fn eglQueryString(dpy: EGLDisplay, name: EGLint) -> *const c_char;
+
+    fn eglGetDisplay(display_id: *mut c_void) -> EGLDisplay;
+    fn eglInitialize(dpy: EGLDisplay, major: *mut EGLint, minor: *mut EGLint) -> EGLBoolean;
+    fn eglTerminate(dpy: EGLDisplay) -> EGLBoolean;
+    fn eglBindAPI(api: EGLenum) -> EGLBoolean;
+
+    fn eglChooseConfig(dpy: EGLDisplay,
+                       attrib_list: *const EGLint,
+                       configs: *mut EGLConfig,
+                       config_size: EGLint,
+                       num_config: *mut EGLint)
+                       -> EGLBoolean;
+    fn eglGetConfigAttrib(dpy: EGLDisplay, config: EGLConfig, attribute: EGLint, value: *mut EGLint)
+                          -> EGLBoolean;
+    fn eglQueryContext(dpy: EGLDisplay, ctx: EGLContext, attribute: EGLint, value: *mut EGLint)
+                       -> EGLBoolean;
+    fn eglCreateContext(dpy: EGLDisplay,
+                        config: EGLConfig,
+                        share_context: EGLContext,
+                        attrib_list: *const EGLint)
+                        -> EGLContext;
+    fn eglDestroyContext(dpy: EGLDisplay, ctx: EGLContext) -> EGLBoolean;
+    fn eglCreatePbufferSurface(dpy: EGLDisplay, config: EGLConfig, attrib_list: *const EGLint)
+                               -> EGLSurface;
+    fn eglDestroySurface(dpy: EGLDisplay, surface: EGLSurface) -> EGLBoolean;
+    fn eglMakeCurrent(dpy: EGLDisplay, draw: EGLSurface, read: EGLSurface, ctx: EGLContext)
+                      -> EGLBoolean;
+    fn eglGetCurrentContext() -> EGLContext;
+    fn eglGetCurrentDisplay() -> EGLDisplay;
+    fn eglGetCurrentSurface(readdraw: EGLint) -> EGLSurface;
+}
+
+// Returns true if the given space-separated extension string contains the given extension.
+fn has_extension(extensions: *const c_char, name: &str) -> bool {
+    if extensions == ptr::null() {
+        return false
+    }
+    let extensions = unsafe { CString::new(extensions, false) };
+    match extensions.as_str() {
+        Some(extensions) => extensions.split(' ').any(|extension| extension == name),
+        None => false,
+    }
+}
+
+// Returns true if the EGL client library supports the given client extension.
+fn has_client_extension(name: &str) -> bool {
+    unsafe {
+        has_extension(eglQueryString(ptr::mut_null(), EGL_EXTENSIONS), name)
+    }
+}
+
+// Looks up an EGL extension function, returning `None` if the driver does not provide it.
+fn get_proc_address(name: &str) -> Option<*const c_void> {
+    let address = name.with_c_str(|name| unsafe { eglGetProcAddress(name) });
+    if address == ptr::null() {
+        None
+    } else {
+        Some(address)
+    }
+}
+
+// Inserts a native fence after the current context's rendering and returns its file descriptor,
+// or `None` if the fence could not be created.
+fn create_native_fence(display: EGLDisplay) -> Option<FileDescriptor> {
+    let (create_sync, destroy_sync, dup_native_fence_fd) =
+        match (get_proc_address("eglCreateSyncKHR"),
+               get_proc_address("eglDestroySyncKHR"),
+               get_proc_address("eglDupNativeFenceFDANDROID")) {
+            (Some(create_sync), Some(destroy_sync), Some(dup_native_fence_fd)) => unsafe {
+                (mem::transmute::<_,EglCreateSyncKHR>(create_sync),
+                 mem::transmute::<_,EglDestroySyncKHR>(destroy_sync),
+                 mem::transmute::<_,EglDupNativeFenceFDANDROID>(dup_native_fence_fd))
+            },
+            _ => return None,
+        };
+
+    let attributes = [ EGL_NONE ];
+    let sync = create_sync(display, EGL_SYNC_NATIVE_FENCE_ANDROID, attributes.as_ptr());
+    if sync == ptr::mut_null() {
+        debug!("eglCreateSyncKHR failed: 0x{:x}", unsafe { eglGetError() });
+        return None
+    }
+
+    // The fence only gets a file descriptor once it has been flushed.
+    gl2::flush();
+    let fd = dup_native_fence_fd(display, sync);
+    destroy_sync(display, sync);
+    if fd == EGL_NO_NATIVE_FENCE_FD_ANDROID {
+        debug!("eglDupNativeFenceFDANDROID failed: 0x{:x}", unsafe { eglGetError() });
+        return None
+    }
+    Some(FileDescriptor::from_raw_fd(fd as int))
+}
+
+// Looks up an extension function, returning `ExtensionUnsupported` with the name of the
+// extension if the driver does not provide it.
+fn get_extension_proc(name: &str, extension: &'static str) -> Result<*const c_void,Error> {
+    match get_proc_address(name) {
+        Some(address) => Ok(address),
+        None => Err(ExtensionUnsupported(extension)),
+    }
+}
+
+// Implementation
+
+/// Which EGL platform a `GraphicsContextBuilder` creates its display on.
+#[deriving(Clone, PartialEq, Show)]
+pub enum EGLPlatform {
+    /// Try the surfaceless platform, then the first EGL device, then the default display.
+    AnyPlatform,
+    /// The Mesa surfaceless platform (`EGL_MESA_platform_surfaceless`).
+    SurfacelessPlatform,
+    /// The EGL device with the given index (`EGL_EXT_platform_device`).
+    DevicePlatform(uint),
+    /// The display returned by `eglGetDisplay(EGL_DEFAULT_DISPLAY)`.
+    DefaultPlatform,
+}
+
+// The number of live `DisplayConnection`s for each EGL display in this process. There is only one
+// `EGLDisplay` per platform, and `eglTerminate` is not reference-counted, so it may only be called
+// once no connection on any task uses the display.
+static mut display_references: *mut Mutex<Vec<(EGLDisplay, uint)>> =
+    0 as *mut Mutex<Vec<(EGLDisplay, uint)>>;
+static mut display_references_initialized: Once = ONCE_INIT;
+
+fn display_references() -> &'static Mutex<Vec<(EGLDisplay, uint)>> {
+    unsafe {
+        display_references_initialized.doit(|| {
+            display_references = mem::transmute(box Mutex::new(Vec::<(EGLDisplay, uint)>::new()));
+        });
+        &*display_references
+    }
+}
+
+// Records another connection to the given display.
+fn retain_display(references: &mut Vec<(EGLDisplay, uint)>, display: EGLDisplay) {
+    match references.iter().position(|&(other, _)| other == display) {
+        Some(index) => {
+            let (_, count) = references[index];
+            references.as_mut_slice()[index] = (display, count + 1);
+        }
+        None => references.push((display, 1)),
+    }
+}
+
+// Forgets a connection to the given display, and returns true if it was the last one.
+fn release_display(references: &mut Vec<(EGLDisplay, uint)>, display: EGLDisplay) -> bool {
+    match references.iter().position(|&(other, _)| other == display) {
+        Some(index) => {
+            let (_, count) = references[index];
+            if count > 1 {
+                references.as_mut_slice()[index] = (display, count - 1);
+                return false
+            }
+            references.remove(index);
+            true
+        }
+        None => true,
+    }
+}
+
+// Returns the attributes contexts are created with by default. EGL contexts have always asked for
+// 8-bit RGBA, unlike the RGB visual `ContextAttributes::default()` describes.
+fn default_attributes() -> ContextAttributes {
+    let mut attributes: ContextAttributes = Default::default();
+    attributes.pixel_format.alpha_bits = 8;
+    attributes
+}
+
+/// An initialized EGL display, shared by every context created on it. The display is terminated
+/// when the last connection to it in the process goes away, unless it was initialized by whoever
+/// made a foreign context current.
+struct DisplayConnection {
+    display: EGLDisplay,
+    // Whether this connection initialized the display and holds a reference to it.
+    owned: bool,
+    // Whether contexts on this display can be made current without a surface.
+    surfaceless: bool,
+    // Whether pbuffers on this display can be created with an sRGB color space.
+    srgb: bool,
+    // Whether contexts on this display can be created with a specific version, profile and flags.
+    create_context: bool,
+    // Whether rendering can be fenced with file descriptors that other processes can wait on.
+    native_fence_sync: bool,
+    // Whether textures can be exported as dma-bufs.
+    dma_buf_export: bool,
+    // Whether dma-bufs can be imported, and whether they can be imported with explicit modifiers.
+    dma_buf_import: bool,
+    dma_buf_import_modifiers: bool,
+    _resource: TrackedResource,
+}
+
+impl DisplayConnection {
+    // Returns a connection to the given initialized display, recording which extensions it has.
+    fn new(display: EGLDisplay, owned: bool) -> DisplayConnection {
+        let extensions = unsafe { eglQueryString(display, EGL_EXTENSIONS) };
+        DisplayConnection {
+            display: display,
+            owned: owned,
+            surfaceless: has_extension(extensions, "EGL_KHR_surfaceless_context"),
+            srgb: has_extension(extensions, "EGL_KHR_gl_colorspace"),
+            create_context: has_extension(extensions, "EGL_KHR_create_context"),
+            native_fence_sync: has_extension(extensions, "EGL_ANDROID_native_fence_sync"),
+            dma_buf_export: has_extension(extensions, "EGL_MESA_image_dma_buf_export") &&
+                has_extension(extensions, "EGL_KHR_gl_texture_2D_image"),
+            dma_buf_import: has_extension(extensions, "EGL_EXT_image_dma_buf_import"),
+            dma_buf_import_modifiers:
+                has_extension(extensions, "EGL_EXT_image_dma_buf_import_modifiers"),
+            _resource: TrackedResource::new(DisplayResource),
+        }
+    }
+}
+
+impl Drop for DisplayConnection {
+    fn drop(&mut self) {
+        if !self.owned {
+            return
+        }
+
+        // Terminate under the lock so that no other task initializes the display meanwhile.
+        let mut references = display_references().lock();
+        if !release_display(&mut *references, self.display) {
+            return
+        }
+        debug!("terminating EGL display {}", self.display);
+        unsafe {
+            eglTerminate(self.display);
+        }
+    }
+}
+
+// The display opened by `AnyPlatform`, shared by every context on this task created without an
+// explicit platform.
+local_data_key!(default_display_connection: Weak<DisplayConnection>)
+
+/// The EGL objects backing a context. These are destroyed when the last clone of the context goes
+/// away.
+struct NativeResources {
+    display: Arc<DisplayConnection>,
+    // The pbuffer the context renders into, or null if the context is surfaceless.
+    surface: EGLSurface,
+    // The draw and read surfaces a foreign context was current on, which belong to whoever
+    // created them.
+    foreign_surfaces: Option<(EGLSurface, EGLSurface)>,
+    pixel_format: PixelFormat,
+    // The EGL context, if we created it. Wrapped contexts are owned by whoever created them.
+    owned_context: Option<EGLContext>,
+    _surface_resource: Option<TrackedResource>,
+    _context_resource: Option<TrackedResource>,
+}
+
+impl Drop for NativeResources {
+    fn drop(&mut self) {
+        let display = self.display.display;
+        unsafe {
+            match self.owned_context {
+                Some(context) => {
+                    if eglGetCurrentContext() == context {
+                        eglMakeCurrent(display, ptr::mut_null(), ptr::mut_null(), ptr::mut_null());
+                    }
+                    eglDestroyContext(display, context);
+                }
+                None => {}
+            }
+            if self.surface != ptr::mut_null() {
+                eglDestroySurface(display, self.surface);
+            }
+        }
+    }
+}
+
+/// EGL interface to 3D graphics contexts.
+///
+/// Clones share the same display, surface and EGL context; these are released when the last
+/// clone is dropped.
+pub struct GraphicsContext {
+    resources: Arc<NativeResources>,
+    context: Arc<EGLContext>,
+    // A context can be sent to another thread but not used from two threads at once.
+    _no_sync: marker::NoSync,
+}
+
+impl GraphicsContext {
+    /// Returns a builder that can create contexts on a specific EGL platform.
+    pub fn builder() -> GraphicsContextBuilder {
+        GraphicsContextBuilder::new()
+    }
+
+    /// Returns the EGL display this context was created on.
+    pub fn display(&self) -> EGLDisplay {
+        self.resources.display.display
+    }
+
+    // Creates a new, possibly shared, EGL context. A pbuffer of the given size is created if the
+    // display does not support surfaceless contexts.
+    fn new_possibly_shared(builder: &GraphicsContextBuilder,
+                           share_context: Option<&GraphicsContext>,
+                           size: Size2D<int>)
+                           -> Result<GraphicsContext,Error> {
+        // Shared contexts must live on the same display as the context they share with.
+        let display = match share_context {
+            None => try!(builder.open_display()),
+            Some(ref share_context) => share_context.resources.display.clone(),
+        };
+
+        let requested = &builder.attributes.pixel_format;
+        unsafe {
+            let config = try!(GraphicsContext::choose_config(&*display,
+                                                             &builder.attributes));
+
+            let mut resources = NativeResources {
+                display: display.clone(),
+                surface: ptr::mut_null(),
+                foreign_surfaces: None,
+                pixel_format: GraphicsContext::granted_pixel_format(display.display, config),
+                owned_context: None,
+                _surface_resource: None,
+                _context_resource: None,
+            };
+
+            if !display.surfaceless {
+                let mut attributes = vec!(
+                    EGL_WIDTH, size.width as EGLint,
+                    EGL_HEIGHT, size.height as EGLint,
+                );
+                let srgb = requested.srgb && display.srgb;
+                if srgb {
+                    attributes.push_all([ EGL_GL_COLORSPACE_KHR, EGL_GL_COLORSPACE_SRGB_KHR ]);
+                }
+                attributes.push(EGL_NONE);
+                let surface = eglCreatePbufferSurface(display.display,
+                                                      config,
+                                                      attributes.as_ptr());
+                if surface == ptr::mut_null() {
+                    debug!("eglCreatePbufferSurface failed: 0x{:x}", eglGetError());
+                    return Err(SurfaceCreationFailed)
+                }
+                resources.surface = surface;
+                resources.pixel_format.srgb = srgb;
+                resources._surface_resource = Some(TrackedResource::new(DrawableResource));
+            }
+
+            let native_share_context = match share_context {
+                None => ptr::mut_null(),
+                Some(share_context) => *share_context.native(),
+            };
+            let context = try!(GraphicsContext::create_context(&*display,
+                                                               config,
+                                                               native_share_context,
+                                                               &builder.attributes));
+            resources.owned_context = Some(context);
+            resources._context_resource = Some(TrackedResource::new(ContextResource));
+
+            Ok(GraphicsContext {
+                resources: Arc::new(resources),
+                context: Arc::new(context),
+                _no_sync: marker::NoSync,
+            })
+        }
+    }
+
+    // Creates an EGL context for the requested API. A specific version, profile or flags need
+    // `EGL_KHR_create_context`.
+    fn create_context(display: &DisplayConnection,
+                      config: EGLConfig,
+                      share_context: EGLContext,
+                      attributes: &ContextAttributes)
+                      -> Result<EGLContext,Error> {
+        let api = match attributes.api {
+            OpenGL => EGL_OPENGL_API,
+            OpenGLES => EGL_OPENGL_ES_API,
+        };
+
+        let mut context_attributes = vec!();
+        if attributes.api == OpenGLES || !attributes.is_legacy() {
+            let needs_extension = attributes.debug || attributes.forward_compatible ||
+                attributes.api == OpenGL || attributes.version.map_or(false, |(_, minor)| {
+                    minor != 0
+                });
+            if needs_extension && !display.create_context {
+                return Err(ExtensionUnsupported("EGL_KHR_create_context"))
+            }
+
+            let (major_version, minor_version) = match attributes.version {
+                Some(version) => version,
+                None if attributes.api == OpenGLES => (2, 0),
+                None => (1, 0),
+            };
+            context_attributes.push_all([ EGL_CONTEXT_MAJOR_VERSION_KHR, major_version as EGLint ]);
+            if display.create_context {
+                let mut flags = 0;
+                if attributes.debug {
+                    flags |= EGL_CONTEXT_OPENGL_DEBUG_BIT_KHR;
+                }
+                if attributes.forward_compatible {
+                    flags |= EGL_CONTEXT_OPENGL_FORWARD_COMPATIBLE_BIT_KHR;
+                }
+                context_attributes.push_all([
+                    EGL_CONTEXT_MINOR_VERSION_KHR, minor_version as EGLint,
+                    EGL_CONTEXT_FLAGS_KHR, flags,
+                ]);
+                if attributes.api == OpenGL {
+                    let profile_mask = if attributes.wants_core_profile() {
+                        EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT_KHR
+                    } else {
+                        EGL_CONTEXT_OPENGL_COMPATIBILITY_PROFILE_BIT_KHR
+                    };
+                    context_attributes.push_all([
+                        EGL_CONTEXT_OPENGL_PROFILE_MASK_KHR, profile_mask,
+                    ]);
+                }
+            }
+        }
+        context_attributes.push(EGL_NONE);
+
+        unsafe {
+            if eglBindAPI(api) == 0 {
+                debug!("eglBindAPI failed: 0x{:x}", eglGetError());
+                return Err(ExtensionUnsupported(match attributes.api {
+                    OpenGL => "OpenGL",
+                    OpenGLES => "OpenGL ES",
+                }))
+            }
+
+            let context = eglCreateContext(display.display,
+                                           config,
+                                           share_context,
+                                           context_attributes.as_ptr());
+            if context == ptr::mut_null() {
+                debug!("eglCreateContext failed for {}: 0x{:x}", attributes, eglGetError());
+                return Err(ContextCreationFailed)
+            }
+            Ok(context)
+        }
+    }
+
+    // Returns the config that best matches the given pixel format and supports the requested API.
+    // Pbuffers are single-buffered, so double buffering is never requested.
+    fn choose_config(display: &DisplayConnection, attributes: &ContextAttributes)
+                     -> Result<EGLConfig,Error> {
+        let pixel_format = &attributes.pixel_format;
+        let surface_type = if display.surfaceless { 0 } else { EGL_PBUFFER_BIT };
+        let renderable_type = match (attributes.api, attributes.version) {
+            (OpenGL, _) => EGL_OPENGL_BIT,
+            (OpenGLES, Some((major_version, _))) if major_version >= 3 => EGL_OPENGL_ES3_BIT_KHR,
+            (OpenGLES, _) => EGL_OPENGL_ES2_BIT,
+        };
+        let mut attributes = vec!(
+            EGL_SURFACE_TYPE, surface_type,
+            EGL_RENDERABLE_TYPE, renderable_type,
+            EGL_RED_SIZE, pixel_format.red_bits as EGLint,
+            EGL_GREEN_SIZE, pixel_format.green_bits as EGLint,
+            EGL_BLUE_SIZE, pixel_format.blue_bits as EGLint,
+            EGL_ALPHA_SIZE, pixel_format.alpha_bits as EGLint,
+            EGL_DEPTH_SIZE, pixel_format.depth_bits as EGLint,
+            EGL_STENCIL_SIZE, pixel_format.stencil_bits as EGLint,
+        );
+        if pixel_format.samples > 0 {
+            attributes.push_all([
+                EGL_SAMPLE_BUFFERS, 1,
+                EGL_SAMPLES, pixel_format.samples as EGLint,
+            ]);
+        }
+        attributes.push(EGL_NONE);
+        GraphicsContext::choose_config_with_attributes(display.display, attributes.as_slice())
+    }
+
+    fn choose_config_with_attributes(display: EGLDisplay, attributes: &[EGLint])
+                                     -> Result<EGLConfig,Error> {
+        unsafe {
+            let mut config = ptr::mut_null();
+            let mut config_count = 0;
+            let result = eglChooseConfig(display,
+                                         attributes.as_ptr(),
+                                         &mut config,
+                                         1,
+                                         &mut config_count);
+            if result == 0 || config_count == 0 {
+                debug!("eglChooseConfig found no config: 0x{:x}", eglGetError());
+                return Err(NoMatchingVisual)
+            }
+            Ok(config)
+        }
+    }
+
+    // Returns the pixel format of the given config. sRGB is a property of the surface rather than
+    // the config, so it is reported as unsupported.
+    fn granted_pixel_format(display: EGLDisplay, config: EGLConfig) -> PixelFormat {
+        let get = |attribute: EGLint| -> u8 {
+            let mut value = 0;
+            unsafe {
+                if eglGetConfigAttrib(display, config, attribute, &mut value) == 0 {
+                    return 0
+                }
+            }
+            value as u8
+        };
+        PixelFormat {
+            red_bits: get(EGL_RED_SIZE),
+            green_bits: get(EGL_GREEN_SIZE),
+            blue_bits: get(EGL_BLUE_SIZE),
+            alpha_bits: get(EGL_ALPHA_SIZE),
+            depth_bits: get(EGL_DEPTH_SIZE),
+            stencil_bits: get(EGL_STENCIL_SIZE),
+            samples: if get(EGL_SAMPLE_BUFFERS) != 0 { get(EGL_SAMPLES) } else { 0 },
+            srgb: false,
+            double_buffered: false,
+        }
+    }
+}
+
+/// Creates EGL graphics contexts with non-default settings, such as an explicit platform.
+pub struct GraphicsContextBuilder {
+    platform: EGLPlatform,
+    attributes: ContextAttributes,
+}
+
+impl GraphicsContextBuilder {
+    /// Returns a builder that picks the first available headless platform.
+    pub fn new() -> GraphicsContextBuilder {
+        GraphicsContextBuilder {
+            platform: AnyPlatform,
+            attributes: default_attributes(),
+        }
+    }
+
+    /// Creates contexts on the given EGL platform.
+    pub fn platform(self, platform: EGLPlatform) -> GraphicsContextBuilder {
+        GraphicsContextBuilder {
+            platform: platform,
+            ..self
+        }
+    }
+
+    /// Requests the given attributes, such as the pixel format, for the contexts this builder
+    /// creates.
+    pub fn attributes(self, attributes: ContextAttributes) -> GraphicsContextBuilder {
+        GraphicsContextBuilder {
+            attributes: attributes,
+            ..self
+        }
+    }
+
+    /// Creates a new offscreen 3D graphics context.
+    pub fn build(&self) -> Result<GraphicsContext,Error> {
+        GraphicsContext::new_possibly_shared(self, None, Size2D(1, 1))
+    }
+
+    /// Creates a new offscreen 3D graphics context shared with the given context. The new context
+    /// uses the same EGL display as the share context.
+    pub fn build_shared(&self, share_context: &GraphicsContext) -> Result<GraphicsContext,Error> {
+        GraphicsContext::new_possibly_shared(self, Some(share_context), Size2D(1, 1))
+    }
+
+    /// Creates a new share context whose framebuffer has the given size.
+    pub fn build_share_context(&self, size: Size2D<int>) -> Result<Context,Error> {
+        let context = try!(GraphicsContext::new_possibly_shared(self, None, size));
+        Context::new_with_context(context, size)
+    }
+
+    // Returns the EGL display to use, initializing it if necessary.
+    fn open_display(&self) -> Result<Arc<DisplayConnection>,Error> {
+        if self.platform == AnyPlatform {
+            // Reuse the default display if another context still holds it.
+            match default_display_connection.get() {
+                Some(weak) => match weak.upgrade() {
+                    Some(connection) => return Ok(connection),
+                    None => {}
+                },
+                None => {}
+            }
+        }
+
+        let display = match self.platform {
+            AnyPlatform => {
+                let mut display = GraphicsContextBuilder::get_surfaceless_display();
+                if display == ptr::mut_null() {
+                    display = GraphicsContextBuilder::get_device_display(0);
+                }
+                if display == ptr::mut_null() {
+                    display = unsafe { eglGetDisplay(ptr::mut_null()) };
+                }
+                display
+            }
+            SurfacelessPlatform => GraphicsContextBuilder::get_surfaceless_display(),
+            DevicePlatform(index) => GraphicsContextBuilder::get_device_display(index),
+            DefaultPlatform => unsafe { eglGetDisplay(ptr::mut_null()) },
+        };
+        if display == ptr::mut_null() {
+            debug!("no EGL display is available on {}", self.platform);
+            return Err(NoDisplay)
+        }
+
+        unsafe {
+            // Initialize under the lock so that no other task terminates the display between
+            // initializing it and recording this connection.
+            let mut references = display_references().lock();
+            let (mut major, mut minor) = (0, 0);
+            if eglInitialize(display, &mut major, &mut minor) == 0 {
+                debug!("eglInitialize failed: 0x{:x}", eglGetError());
+                return Err(NoDisplay)
+            }
+            debug!("initialized EGL {}.{} on {}", major, minor, self.platform);
+            retain_display(&mut *references, display);
+
+            let connection = Arc::new(DisplayConnection::new(display, true));
+            if self.platform == AnyPlatform {
+                default_display_connection.replace(Some(connection.downgrade()));
+            }
+            Ok(connection)
+        }
+    }
+
+    // Returns the Mesa surfaceless display, or null if it is unsupported.
+    fn get_surfaceless_display() -> EGLDisplay {
+        if !has_client_extension("EGL_MESA_platform_surfaceless") {
+            return ptr::mut_null()
+        }
+        match get_proc_address("eglGetPlatformDisplayEXT") {
+            None => ptr::mut_null(),
+            Some(address) => unsafe {
+                let get_platform_display: EglGetPlatformDisplayEXT = mem::transmute(address);
+                let attributes = [ EGL_NONE ];
+                get_platform_display(EGL_PLATFORM_SURFACELESS_MESA, ptr::mut_null(), &attributes[0])
+            },
+        }
+    }
+
+    // Returns the display for the EGL device with the given index, or null if there is no such
+    // device.
+    fn get_device_display(index: uint) -> EGLDisplay {
+        if !has_client_extension("EGL_EXT_platform_device") {
+            return ptr::mut_null()
+        }
+        let (query_devices, get_platform_display) =
+            match (get_proc_address("eglQueryDevicesEXT"),
+                   get_proc_address("eglGetPlatformDisplayEXT")) {
+                (Some(query_devices), Some(get_platform_display)) => {
+                    unsafe {
+                        let query_devices: EglQueryDevicesEXT = mem::transmute(query_devices);
+                        let get_platform_display: EglGetPlatformDisplayEXT =
+                            mem::transmute(get_platform_display);
+                        (query_devices, get_platform_display)
+                    }
+                }
+                _ => return ptr::mut_null(),
+            };
+
+        let mut device_count = 0;
+        if query_devices(0, ptr::mut_null(), &mut device_count) == 0 ||
+                index >= device_count as uint {
+            return ptr::mut_null()
+        }
+        let mut devices = Vec::from_elem(device_count as uint, ptr::mut_null());
+        if query_devices(device_count, devices.as_mut_ptr(), &mut device_count) == 0 {
+            return ptr::mut_null()
+        }
+        let attributes = [ EGL_NONE ];
+        get_platform_display(EGL_PLATFORM_DEVICE_EXT, *devices.get(index), &attributes[0])
+    }
+}
+
+local_data_key!(current_context: CurrentContext<NativeResources, EGLContext>)
+
+impl GraphicsContextMethods<EGLContext> for GraphicsContext {
+    /// Wraps the given instance of the native EGL graphics context, bumping the reference count in
+    /// the process. The context must have been created on the default headless display, and is
+    /// not destroyed when this `GraphicsContext` goes away.
+    fn wrap(instance: Arc<EGLContext>) -> GraphicsContext {
+        let display = match GraphicsContextBuilder::new().open_display() {
+            Ok(display) => display,
+            Err(error) => fail!("failed to wrap context: {}", error),
+        };
+        if !display.surfaceless {
+            fail!("failed to wrap context: the EGL display does not support surfaceless contexts");
+        }
+
+        // Look up the config the context was created with.
+        let mut config_id = 0;
+        unsafe {
+            eglQueryContext(display.display, *instance, EGL_CONFIG_ID, &mut config_id);
+        }
+        let pixel_format =
+            match GraphicsContext::choose_config_with_attributes(display.display,
+                                                                 [ EGL_CONFIG_ID, config_id,
+                                                                   EGL_NONE ]) {
+                Ok(config) => GraphicsContext::granted_pixel_format(display.display, config),
+                Err(_) => default_attributes().pixel_format,
+            };
+
+        GraphicsContext {
+            resources: Arc::new(NativeResources {
+                display: display,
+                surface: ptr::mut_null(),
+                foreign_surfaces: None,
+                pixel_format: pixel_format,
+                owned_context: None,
+                _surface_resource: None,
+                _context_resource: None,
+            }),
+            context: instance.clone(),
+            _no_sync: marker::NoSync,
+        }
+    }
+
+    /// Returns the underlying native 3D context. The native context is only valid for as long as
+    /// this context or one of its clones is alive.
+    fn native(&self) -> Arc<EGLContext> {
+        self.context.clone()
+    }
+
+    /// Creates a new offscreen 3D graphics context with 8-bit RGBA and a 24-bit depth buffer.
+    fn try_new() -> Result<GraphicsContext,Error> {
+        GraphicsContextBuilder::new().build()
+    }
+
+    /// Creates a new offscreen 3D graphics context with 8-bit RGBA and a 24-bit depth buffer,
+    /// shared with this context.
+    fn try_new_shared(&self) -> Result<GraphicsContext,Error> {
+        GraphicsContextBuilder::new().build_shared(self)
+    }
+
+    /// Creates a new offscreen 3D graphics context with the given attributes.
+    fn try_new_with_attributes(attributes: &ContextAttributes) -> Result<GraphicsContext,Error> {
+        GraphicsContextBuilder::new().attributes(attributes.clone()).build()
+    }
+
+    /// Creates a new offscreen 3D graphics context with the given attributes, shared with this
+    /// context.
+    fn try_new_shared_with_attributes(&self, attributes: &ContextAttributes)
+                                      -> Result<GraphicsContext,Error> {
+        GraphicsContextBuilder::new().attributes(attributes.clone()).build_shared(self)
+    }
+
+    /// Makes this context the current context.
+    fn try_make_current(&self) -> Result<(),Error> {
+        unsafe {
+            let (draw, read) = match self.resources.foreign_surfaces {
+                Some(surfaces) => surfaces,
+                None => (self.resources.surface, self.resources.surface),
+            };
+            let result = eglMakeCurrent(self.display(), draw, read, *self.context);
+            if result == 0 {
+                debug!("eglMakeCurrent failed: 0x{:x}", eglGetError());
+                return Err(MakeCurrentFailed)
+            }
+            current_context.replace(Some(CurrentContext::new(&self.resources, &self.context)));
+            Ok(())
+        }
+    }
+
+    fn try_release_current(&self) -> Result<(),Error> {
+        if !self.is_current() {
+            return Ok(())
+        }
+        unsafe {
+            let result = eglMakeCurrent(self.display(),
+                                        ptr::mut_null(),
+                                        ptr::mut_null(),
+                                        ptr::mut_null());
+            if result == 0 {
+                debug!("eglMakeCurrent failed: 0x{:x}", eglGetError());
+                return Err(MakeCurrentFailed)
+            }
+            current_context.replace(None);
+            Ok(())
+        }
+    }
+
+    fn is_current(&self) -> bool {
+        unsafe {
+            eglGetCurrentContext() == *self.context
+        }
+    }
+
+    /// Returns the current context. Contexts this task made current are returned along with their
+    /// resources; any other EGL context is returned with the display and surfaces it is current
+    /// on, none of which are terminated or destroyed when the context goes away.
+    fn current() -> Option<GraphicsContext> {
+        let native = unsafe { eglGetCurrentContext() };
+        if native == ptr::mut_null() {
+            return None
+        }
+        match current_context.get().and_then(|current| current.upgrade(&native)) {
+            Some((resources, context)) => {
+                return Some(GraphicsContext {
+                    resources: resources,
+                    context: context,
+                    _no_sync: marker::NoSync,
+                })
+            }
+            None => {}
+        }
+
+        let (display, draw, read) = unsafe {
+            (eglGetCurrentDisplay(), eglGetCurrentSurface(EGL_DRAW), eglGetCurrentSurface(EGL_READ))
+        };
+        let mut config_id = 0;
+        unsafe {
+            eglQueryContext(display, native, EGL_CONFIG_ID, &mut config_id);
+        }
+        let pixel_format =
+            match GraphicsContext::choose_config_with_attributes(display,
+                                                                 [ EGL_CONFIG_ID, config_id,
+                                                                   EGL_NONE ]) {
+                Ok(config) => GraphicsContext::granted_pixel_format(display, config),
+                Err(_) => default_attributes().pixel_format,
+            };
+        Some(GraphicsContext {
+            resources: Arc::new(NativeResources {
+                display: Arc::new(DisplayConnection::new(display, false)),
+                surface: ptr::mut_null(),
+                foreign_surfaces: Some((draw, read)),
+                pixel_format: pixel_format,
+                owned_context: None,
+                _surface_resource: None,
+                _context_resource: None,
+            }),
+            context: Arc::new(native),
+            _no_sync: marker::NoSync,
+        })
+    }
+
+    /// Returns the pixel format of the config the context was created with.
+    fn pixel_format(&self) -> PixelFormat {
+        self.resources.pixel_format.clone()
+    }
+
+    fn get_proc_address(&self, name: &str) -> Option<*const c_void> {
+        get_proc_address(name)
+    }
+}
+
+impl Clone for GraphicsContext {
+    fn clone(&self) -> GraphicsContext {
+        GraphicsContext {
+            resources: self.resources.clone(),
+            context: self.context.clone(),
+            _no_sync: marker::NoSync,
+        }
+    }
+}
+
+/// An EGL context rendering into a texture through a framebuffer. `id()` names the texture as
+/// dma-bufs where the display can export them, and otherwise names a shared-memory region that
+/// each `flush()` copies the frame into. Either way the ID stays valid, and unique among the
+/// surfaces on this machine, until the context is resized or dropped.
+pub struct Context {
+    context: GraphicsContext,
+    framebuffer: GLuint,
+    texture: GLuint,
+    publication: Publication,
+    generation: uint,
+    size: Size2D<int>,
+    _framebuffer_resource: TrackedResource,
+    _texture_resource: TrackedResource,
+}
+
+// How the texture of a share context is made available to other processes.
+enum Publication {
+    // The texture exported as dma-bufs, with the descriptors that keep them open.
+    ExportedDmaBuf(DmaBuf, Vec<FileDescriptor>),
+    // A shared-memory region the texture is copied into on every flush.
+    CopiedToShm(ShmSurface),
+    // Nothing yet, while the texture is being set up.
+    Unpublished,
+}
+
+impl Context {
+    // Creates the framebuffer and texture for a share context of the given size, and leaves the
+    // framebuffer bound.
+    fn new_with_context(context: GraphicsContext, size: Size2D<int>) -> Result<Context,Error> {
+        try!(context.try_make_current());
+
+        let framebuffer = gl2::gen_framebuffers(1)[0];
+        gl2::bind_framebuffer(FRAMEBUFFER, framebuffer);
+
+        // From here on, dropping the context cleans up after us.
+        let texture = gl2::gen_textures(1)[0];
+        let mut share_context = Context {
+            context: context,
+            framebuffer: framebuffer,
+            texture: texture,
+            publication: Unpublished,
+            generation: 0,
+            size: size,
+            _framebuffer_resource: TrackedResource::new(FramebufferResource),
+            _texture_resource: TrackedResource::new(TextureResource),
+        };
+        try!(Context::attach_texture(texture, size));
+        share_context.publication = try!(Context::publish(&share_context.context, texture, size));
+        Ok(share_context)
+    }
+
+    // Allocates storage of the given size for the texture and attaches it to the bound
+    // framebuffer.
+    fn attach_texture(texture: GLuint, size: Size2D<int>) -> Result<(),Error> {
+        gl2::bind_texture(TEXTURE_2D, texture);
+        gl2::tex_parameter_i(TEXTURE_2D, TEXTURE_MAG_FILTER, LINEAR as GLint);
+        gl2::tex_parameter_i(TEXTURE_2D, TEXTURE_MIN_FILTER, NEAREST as GLint);
+        gl2::tex_image_2d(TEXTURE_2D,
+                          0,
+                          RGBA as GLint,
+                          size.width as GLsizei,
+                          size.height as GLsizei,
+                          0,
+                          RGBA,
+                          UNSIGNED_BYTE,
+                          None);
+        gl2::bind_texture(TEXTURE_2D, 0);
+
+        gl2::framebuffer_texture_2d(FRAMEBUFFER, COLOR_ATTACHMENT0, TEXTURE_2D, texture, 0);
+        let status = gl2::check_framebuffer_status(FRAMEBUFFER);
+        if status != FRAMEBUFFER_COMPLETE {
+            return Err(FramebufferIncomplete(status))
+        }
+        Ok(())
+    }
+
+    // Exports the texture as dma-bufs if the display supports it, and otherwise creates the
+    // shared memory it will be copied into.
+    fn publish(context: &GraphicsContext, texture: GLuint, size: Size2D<int>)
+               -> Result<Publication,Error> {
+        if !context.resources.display.dma_buf_export {
+            return Ok(CopiedToShm(try!(ShmSurface::try_new(size))))
+        }
+        let (buffer, fds) = try!(Context::export_dma_buf(context, texture));
+        Ok(ExportedDmaBuf(buffer, fds))
+    }
+
+    // Exports the texture as dma-bufs, returning the buffer together with the new file
+    // descriptors it names.
+    fn export_dma_buf(context: &GraphicsContext, texture: GLuint)
+                      -> Result<(DmaBuf, Vec<FileDescriptor>),Error> {
+        let display = context.resources.display.display;
+        let (query_image, export_image): (EglExportDMABUFImageQueryMESA,
+                                          EglExportDMABUFImageMESA) = unsafe {
+            (mem::transmute(try!(get_extension_proc("eglExportDMABUFImageQueryMESA",
+                                                    "EGL_MESA_image_dma_buf_export"))),
+             mem::transmute(try!(get_extension_proc("eglExportDMABUFImageMESA",
+                                                    "EGL_MESA_image_dma_buf_export"))))
+        };
+
+        let image = try!(Image::new(display,
+                                    *context.context,
+                                    EGL_GL_TEXTURE_2D_KHR,
+                                    texture as EGLClientBuffer,
+                                    [ EGL_NONE ]));
+
+        let (mut fourcc, mut plane_count) = (0, 0);
+        let mut modifiers = [ DRM_FORMAT_MOD_INVALID, ..MAX_DMA_BUF_PLANES ];
+        if query_image(display, image.image, &mut fourcc, &mut plane_count,
+                       modifiers.as_mut_ptr()) == 0 {
+            debug!("eglExportDMABUFImageQueryMESA failed: 0x{:x}", unsafe { eglGetError() });
+            return Err(SurfaceCreationFailed)
+        }
+        let plane_count = plane_count as uint;
+        if plane_count > MAX_DMA_BUF_PLANES {
+            debug!("cannot export an image with {} planes", plane_count);
+            return Err(SurfaceCreationFailed)
+        }
+
+        let mut fds = [ -1 as c_int, ..MAX_DMA_BUF_PLANES ];
+        let mut strides = [ 0 as EGLint, ..MAX_DMA_BUF_PLANES ];
+        let mut offsets = [ 0 as EGLint, ..MAX_DMA_BUF_PLANES ];
+        if export_image(display, image.image, fds.as_mut_ptr(), strides.as_mut_ptr(),
+                        offsets.as_mut_ptr()) == 0 {
+            debug!("eglExportDMABUFImageMESA failed: 0x{:x}", unsafe { eglGetError() });
+            return Err(SurfaceCreationFailed)
+        }
+
+        let owned_fds = range(0, plane_count).map(|plane| {
+            FileDescriptor::from_raw_fd(fds[plane] as int)
+        }).collect();
+        let buffer = DmaBuf {
+            fourcc: fourcc as u32,
+            modifier: modifiers[0],
+            planes: range(0, plane_count).map(|plane| {
+                DmaBufPlane {
+                    fd: fds[plane] as int,
+                    offset: offsets[plane] as u32,
+                    stride: strides[plane] as u32,
+                }
+            }).collect(),
+        };
+        Ok((buffer, owned_fds))
+    }
+
+    /// Returns the graphics context this share context renders with.
+    pub fn graphics_context<'a>(&'a self) -> &'a GraphicsContext {
+        &self.context
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        if self.context.try_make_current().is_ok() {
+            gl2::bind_framebuffer(FRAMEBUFFER, 0);
+            gl2::delete_frame_buffers([self.framebuffer]);
+            gl2::delete_textures([self.texture]);
+        }
+    }
+}
+
+impl ShareContext for Context {
+    fn try_new(size: Size2D<int>) -> Result<Context,Error> {
+        GraphicsContextBuilder::new().build_share_context(size)
+    }
+
+    /// Renders into a new texture of the given size, which has a new name and therefore a new ID.
+    /// If the texture cannot be attached or published, the context keeps rendering into the old
+    /// one.
+    fn try_resize(&mut self, size: Size2D<int>) -> Result<(),Error> {
+        try!(self.context.try_make_current());
+
+        // Generate the new texture before deleting the old one so that the name is not reused.
+        let texture = gl2::gen_textures(1)[0];
+        gl2::bind_framebuffer(FRAMEBUFFER, self.framebuffer);
+        let publication = Context::attach_texture(texture, size).and_then(|()| {
+            Context::publish(&self.context, texture, size)
+        });
+        let publication = match publication {
+            Ok(publication) => publication,
+            Err(error) => {
+                gl2::framebuffer_texture_2d(FRAMEBUFFER,
+                                            COLOR_ATTACHMENT0,
+                                            TEXTURE_2D,
+                                            self.texture,
+                                            0);
+                gl2::delete_textures([texture]);
+                return Err(error)
+            }
+        };
+
+        let old_texture = mem::replace(&mut self.texture, texture);
+        gl2::delete_textures([old_texture]);
+        self.publication = publication;
+        self.generation += 1;
+        self.size = size;
+        Ok(())
+    }
+
+    /// Copies the frame into shared memory if the texture could not be exported. Otherwise returns
+    /// a native fence if the display supports `EGL_ANDROID_native_fence_sync`, and finishes
+    /// rendering if it does not.
+    fn flush(&self) -> SyncToken {
+        // Fences and finishes apply to the current context, so it has to be this one.
+        self.context.make_current();
+        match self.publication {
+            CopiedToShm(ref surface) => {
+                gl2::bind_framebuffer(FRAMEBUFFER, self.framebuffer);
+                return surface.copy_from_framebuffer(self.size)
+            }
+            ExportedDmaBuf(..) | Unpublished => {}
+        }
+
+        let display = &self.context.resources.display;
+        if display.native_fence_sync {
+            match create_native_fence(display.display) {
+                Some(fence) => return NativeFenceSync(fence),
+                None => debug!("falling back to glFinish"),
+            }
+        }
+        gl2::finish();
+        FinishedSync
+    }
+
+    fn id(&self) -> SurfaceId {
+        match self.publication {
+            ExportedDmaBuf(ref buffer, _) => DmaBufId(buffer.clone()),
+            CopiedToShm(ref surface) => surface.id(),
+            Unpublished => fail!("share context has no surface to publish"),
+        }
+    }
+
+    fn generation(&self) -> uint {
+        self.generation
+    }
+
+    fn size(&self) -> Size2D<int> {
+        self.size
+    }
+
+    fn format(&self) -> SurfaceFormat {
+        RGBA8Format
+    }
+
+    fn framebuffer(&self) -> GLuint {
+        self.framebuffer
+    }
+
+    /// Returns the stride the driver chose for exported dma-bufs, which may include padding.
+    fn stride(&self) -> uint {
+        match self.publication {
+            ExportedDmaBuf(ref buffer, _) => buffer.planes[0].stride as uint,
+            CopiedToShm(_) | Unpublished => self.size.width as uint * 4,
+        }
+    }
+}
+
+// An EGL image, destroyed when it goes out of scope. Textures bound to the image keep the
+// underlying buffer alive on their own.
+struct Image {
+    display: EGLDisplay,
+    image: EGLImageKHR,
+    destroy_image: EglDestroyImageKHR,
+}
+
+impl Image {
+    fn new(display: EGLDisplay,
+           context: EGLContext,
+           target: EGLenum,
+           buffer: EGLClientBuffer,
+           attributes: &[EGLint])
+           -> Result<Image,Error> {
+        let (create_image, destroy_image): (EglCreateImageKHR, EglDestroyImageKHR) = unsafe {
+            (mem::transmute(try!(get_extension_proc("eglCreateImageKHR", "EGL_KHR_image_base"))),
+             mem::transmute(try!(get_extension_proc("eglDestroyImageKHR", "EGL_KHR_image_base"))))
+        };
+        let image = create_image(display, context, target, buffer, attributes.as_ptr());
+        if image == ptr::mut_null() {
+            debug!("eglCreateImageKHR failed: 0x{:x}", unsafe { eglGetError() });
+            return Err(SurfaceCreationFailed)
+        }
+        Ok(Image {
+            display: display,
+            image: image,
+            destroy_image: destroy_image,
+        })
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        (self.destroy_image)(self.display, self.image);
+    }
+}
+
+/// Opens a surface published by an EGL share context in another process, whichever way it was
+/// published.
+pub enum SurfaceReader {
+    /// A surface shared as dma-bufs.
+    DmaBufReader(DmaBufSurfaceReader),
+    /// A surface copied through shared memory.
+    ShmReader(ShmSurfaceReader<GraphicsContext>),
+}
+
+impl SharedSurfaceReader<GraphicsContext> for SurfaceReader {
+    /// Opens a `DmaBufId` or a `ShmId`.
+    fn try_open(context: &GraphicsContext, id: &SurfaceId, size: Size2D<int>)
+                -> Result<SurfaceReader,Error> {
+        match *id {
+            DmaBufId(_) => Ok(DmaBufReader(try!(SharedSurfaceReader::try_open(context, id, size)))),
+            ShmId(_) => Ok(ShmReader(try!(SharedSurfaceReader::try_open(context, id, size)))),
+            _ => Err(IncompatibleSurface),
+        }
+    }
+
+    fn texture(&self) -> GLuint {
+        match *self {
+            DmaBufReader(ref reader) => reader.texture(),
+            ShmReader(ref reader) => reader.texture(),
+        }
+    }
+
+    fn update(&self) {
+        match *self {
+            DmaBufReader(ref reader) => reader.update(),
+            ShmReader(ref reader) => reader.update(),
+        }
+    }
+
+    fn read_pixels(&self) -> Vec<u8> {
+        match *self {
+            DmaBufReader(ref reader) => reader.read_pixels(),
+            ShmReader(ref reader) => reader.read_pixels(),
+        }
+    }
+}
+
+/// Imports a surface shared as dma-bufs, by a `Context` in another process or by any other
+/// dma-buf producer, such as a compositor or video decoder. The buffer is bound directly to a
+/// texture in the receiving context, so no copies are made.
+pub struct DmaBufSurfaceReader {
+    context: GraphicsContext,
+    texture: GLuint,
+    size: Size2D<int>,
+    _texture_resource: TrackedResource,
+}
+
+impl SharedSurfaceReader<GraphicsContext> for DmaBufSurfaceReader {
+    /// Imports the buffer named by a `DmaBufId`. The file descriptors are not consumed; their
+    /// owner can close them once this returns.
+    fn try_open(context: &GraphicsContext, id: &SurfaceId, size: Size2D<int>)
+                -> Result<DmaBufSurfaceReader,Error> {
+        let buffer = match *id {
+            DmaBufId(ref buffer) => buffer,
+            _ => return Err(IncompatibleSurface),
+        };
+        let display = &context.resources.display;
+        if !display.dma_buf_import {
+            return Err(ExtensionUnsupported("EGL_EXT_image_dma_buf_import"))
+        }
+        let explicit_modifier = buffer.modifier != DRM_FORMAT_MOD_INVALID;
+        if explicit_modifier && !display.dma_buf_import_modifiers {
+            return Err(ExtensionUnsupported("EGL_EXT_image_dma_buf_import_modifiers"))
+        }
+        if buffer.planes.len() > MAX_DMA_BUF_PLANES {
+            debug!("cannot import a buffer with {} planes", buffer.planes.len());
+            return Err(SurfaceCreationFailed)
+        }
+        let image_target_texture: GlEGLImageTargetTexture2DOES = unsafe {
+            mem::transmute(try!(get_extension_proc("glEGLImageTargetTexture2DOES",
+                                                   "GL_OES_EGL_image")))
+        };
+
+        let mut attributes = vec!(
+            EGL_WIDTH, size.width as EGLint,
+            EGL_HEIGHT, size.height as EGLint,
+            EGL_LINUX_DRM_FOURCC_EXT, buffer.fourcc as EGLint,
+        );
+        for (plane, names) in buffer.planes.iter().zip(EGL_DMA_BUF_PLANE_ATTRIBUTES.iter()) {
+            attributes.push_all([
+                names[0], plane.fd as EGLint,
+                names[1], plane.offset as EGLint,
+                names[2], plane.stride as EGLint,
+            ]);
+            if explicit_modifier {
+                attributes.push_all([
+                    names[3], buffer.modifier as u32 as EGLint,
+                    names[4], (buffer.modifier >> 32) as u32 as EGLint,
+                ]);
+            }
+        }
+        attributes.push(EGL_NONE);
+        let image = try!(Image::new(display.display,
+                                    ptr::mut_null(),
+                                    EGL_LINUX_DMA_BUF_EXT,
+                                    ptr::mut_null(),
+                                    attributes.as_slice()));
+
+        try!(context.try_make_current());
+        let texture = gl2::gen_textures(1)[0];
+        let reader = DmaBufSurfaceReader {
+            context: context.clone(),
+            texture: texture,
+            size: size,
+            _texture_resource: TrackedResource::new(TextureResource),
+        };
+        gl2::bind_texture(TEXTURE_2D, texture);
+        gl2::tex_parameter_i(TEXTURE_2D, TEXTURE_MAG_FILTER, LINEAR as GLint);
+        gl2::tex_parameter_i(TEXTURE_2D, TEXTURE_MIN_FILTER, NEAREST as GLint);
+        image_target_texture(TEXTURE_2D, image.image);
+        gl2::bind_texture(TEXTURE_2D, 0);
+        Ok(reader)
+    }
+
+    fn texture(&self) -> GLuint {
+        self.texture
+    }
+
+    fn update(&self) {
+        // The texture is backed by the dma-buf itself, so it is always up to date.
+    }
+
+    fn read_pixels(&self) -> Vec<u8> {
+        self.context.make_current();
+
+        let framebuffer = gl2::gen_framebuffers(1)[0];
+        gl2::bind_framebuffer(FRAMEBUFFER, framebuffer);
+        gl2::framebuffer_texture_2d(FRAMEBUFFER, COLOR_ATTACHMENT0, TEXTURE_2D, self.texture, 0);
+        let status = gl2::check_framebuffer_status(FRAMEBUFFER);
+        if status != FRAMEBUFFER_COMPLETE {
+            fail!("failed to read shared surface: {}", FramebufferIncomplete(status));
+        }
+        let pixels = gl2::read_pixels(0,
+                                      0,
+                                      self.size.width as GLsizei,
+                                      self.size.height as GLsizei,
+                                      RGBA,
+                                      UNSIGNED_BYTE);
+        gl2::bind_framebuffer(FRAMEBUFFER, 0);
+        gl2::delete_frame_buffers([framebuffer]);
+        pixels
+    }
+}
+
+impl Drop for DmaBufSurfaceReader {
+    fn drop(&mut self) {
+        if self.context.try_make_current().is_ok() {
+            gl2::delete_textures([self.texture]);
+        }
+    }
+}
+
+#[test]
+fn share_contexts_round_trip_through_their_ids() {
+    use opengles::gl2::COLOR_BUFFER_BIT;
+
+    // Mesa's software rasterizer on the surfaceless platform needs no GPU, so this runs on CI.
+    let share_context: Context = ShareContext::new(Size2D(4, 2));
+    let id = share_context.id();
+    if share_context.context.resources.display.dma_buf_export {
+        match id {
+            DmaBufId(_) => {}
+            _ => fail!("expected an exported dma-buf, got {}", id),
+        }
+    }
+    assert_eq!(share_context.id(), id);
+
+    share_context.context.make_current();
+    gl2::clear_color(0.0, 0.0, 1.0, 1.0);
+    gl2::clear(COLOR_BUFFER_BIT);
+    share_context.flush().wait().unwrap();
+
+    let receiver: GraphicsContext = GraphicsContextMethods::new();
+    let reader: SurfaceReader = SharedSurfaceReader::open(&receiver, &id, Size2D(4, 2));
+    assert_eq!(reader.read_pixels(), Vec::from_fn(4 * 2 * 4, |i| [0u8, 0, 255, 255][i % 4]));
+}
+
+#[test]
+fn udmabufs_can_be_imported_without_a_gpu() {
+    use udmabuf;
+
+    static DRM_FORMAT_ABGR8888: u32 = 0x34324241;
+
+    let context: GraphicsContext = GraphicsContextMethods::new();
+    if !context.resources.display.dma_buf_import {
+        return
+    }
+
+    // Little-endian ABGR8888 is RGBA in memory. Nothing to test without the udmabuf driver.
+    let pixels = Vec::from_fn(4 * 2 * 4, |i| [255u8, 0, 0, 255][i % 4]);
+    let fd = match udmabuf::create(pixels.as_slice()) {
+        Some(fd) => fd,
+        None => return,
+    };
+    let buffer = DmaBuf {
+        fourcc: DRM_FORMAT_ABGR8888,
+        modifier: DRM_FORMAT_MOD_INVALID,
+        planes: vec!(DmaBufPlane { fd: fd.as_raw_fd(), offset: 0, stride: 4 * 4 }),
+    };
+    let reader: SurfaceReader =
+        SharedSurfaceReader::open(&context, &DmaBufId(buffer), Size2D(4, 2));
+    assert_eq!(reader.read_pixels(), pixels);
+}
@@ -7,25 +7,78 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use context::GraphicsContextMethods;
+//! The GLX backend, rendering into X pixmaps that other clients of the same X server can read.
+//!
+//! Where the server supports DRI3, share contexts publish their pixmap as a dma-buf instead, so
+//! that consumers need not share the producer's X connection, and `SurfaceReader` imports it back
+//! into a pixmap on the consumer's connection.
+//!
+//! Embedders that hand contexts off between threads must call `init_xlib_threads()` before making
+//! any other Xlib call.
 
-use libc::{c_char, c_int, c_long, c_uint, c_ulong, c_void};
+use base::{BGRA8Format, DRM_FORMAT_MOD_INVALID, DmaBuf, DmaBufId, DmaBufPlane, ShareContext};
+use base::{SharedSurfaceReader, SurfaceFormat, SurfaceId, X11DrawableId};
+use attributes::{ContextAttributes, OpenGL, OpenGLES, PixelFormat};
+use context::{CurrentContext, GraphicsContextMethods};
+use error::{ContextCreationFailed, Error, MakeCurrentFailed, NoMatchingVisual};
+use error::{ExtensionUnsupported, IncompatibleSurface, NoDisplay, SurfaceCreationFailed};
+use fd::FileDescriptor;
+use fence::{FinishedSync, SyncToken};
+use resources::{ContextResource, DisplayResource, DrawableResource, TextureResource};
+use resources::TrackedResource;
+
+use geom::size::Size2D;
+use libc::{c_char, c_int, c_long, c_uint, c_ulong, c_void, dup, free};
+use opengles::gl2::{GLint, GLsizei, GLuint, LINEAR, NEAREST, RGBA, TEXTURE_2D};
+use opengles::gl2::{TEXTURE_MAG_FILTER, TEXTURE_MIN_FILTER, UNSIGNED_BYTE};
+use opengles::gl2;
+use std::c_str::{CString, ToCStr};
+use std::default::Default;
+use std::kinds::marker;
+use std::mem;
 use std::ptr;
-use sync::Arc;
+use std::sync::atomics::{AtomicBool, INIT_ATOMIC_BOOL, SeqCst};
+use std::sync::one::{Once, ONCE_INIT};
+use sync::{Arc, Mutex, Weak};
 
 // Constants.
 
-static GLX_RGBA: c_int = 4;
-/*static GLX_RED_SIZE: c_int = 8;
+static GLX_DOUBLEBUFFER: c_int = 5;
+static GLX_RED_SIZE: c_int = 8;
 static GLX_GREEN_SIZE: c_int = 9;
-static GLX_BLUE_SIZE: c_int = 10;*/
+static GLX_BLUE_SIZE: c_int = 10;
+static GLX_ALPHA_SIZE: c_int = 11;
 static GLX_DEPTH_SIZE: c_int = 12;
+static GLX_STENCIL_SIZE: c_int = 13;
+static GLX_SAMPLE_BUFFERS: c_int = 100000;
+static GLX_SAMPLES: c_int = 100001;
+static GLX_FRAMEBUFFER_SRGB_CAPABLE_ARB: c_int = 0x20b2;
+
+static GLX_DRAWABLE_TYPE: c_int = 0x8010;
+static GLX_RENDER_TYPE: c_int = 0x8011;
+static GLX_FBCONFIG_ID: c_int = 0x8013;
+static GLX_RGBA_TYPE: c_int = 0x8014;
+static GLX_PRESERVED_CONTENTS: c_int = 0x801b;
+static GLX_PBUFFER_HEIGHT: c_int = 0x8040;
+static GLX_PBUFFER_WIDTH: c_int = 0x8041;
+
+static GLX_CONTEXT_MAJOR_VERSION_ARB: c_int = 0x2091;
+static GLX_CONTEXT_MINOR_VERSION_ARB: c_int = 0x2092;
+static GLX_CONTEXT_FLAGS_ARB: c_int = 0x2094;
+static GLX_CONTEXT_PROFILE_MASK_ARB: c_int = 0x9126;
 
-static mut ATTRIBUTES: [c_int, ..4] = [
-    GLX_RGBA,
-    GLX_DEPTH_SIZE, 24,
-    0,
-];
+static GLX_CONTEXT_DEBUG_BIT_ARB: c_int = 0x0001;
+static GLX_CONTEXT_FORWARD_COMPATIBLE_BIT_ARB: c_int = 0x0002;
+static GLX_CONTEXT_CORE_PROFILE_BIT_ARB: c_int = 0x0001;
+static GLX_CONTEXT_COMPATIBILITY_PROFILE_BIT_ARB: c_int = 0x0002;
+static GLX_CONTEXT_ES2_PROFILE_BIT_EXT: c_int = 0x0004;
+
+static GLX_RGBA_BIT: c_int = 0x0001;
+static GLX_PIXMAP_BIT: c_int = 0x0002;
+static GLX_PBUFFER_BIT: c_int = 0x0004;
+
+static DRM_FORMAT_XRGB8888: u32 = 0x34325258;
+static DRM_FORMAT_ARGB8888: u32 = 0x34325241;
 
 // External bindings to Xlib.
 
@@ -34,10 +87,12 @@ struct _XPrivate;
 struct _XrmHashBucketRec;
 struct Depth;
 struct GLXContextOpaque;
+struct GLXFBConfigOpaque;
 struct Visual;
+struct XErrorEvent;
 
 // Sadly we need to copy some of this definition in here because the Xlib macros need to access it.
-struct Display {
+pub struct Display {
     _ext_data: *mut XExtData,
     _private1: *mut _XPrivate,
     _fd: c_int,
@@ -112,7 +167,7 @@ struct XVisualInfo {
     _visual: *mut Visual,
     _visualid: VisualID,
     _screen: c_int,
-    _depth: c_int,
+    depth: c_int,
     _class: c_int,
     _red_mask: c_ulong,
     _green_mask: c_ulong,
@@ -126,29 +181,231 @@ type Colormap = XID;
 type Drawable = c_uint;                 // compatible with Window
 type GC = *mut c_void;
 pub type GLXContext = *mut GLXContextOpaque;
-type GLXDrawable = c_uint;              // compatible with GLXPixmap
+type GLXDrawable = c_uint;              // compatible with GLXPixmap and GLXPbuffer
+type GLXFBConfig = *mut GLXFBConfigOpaque;
+type GLXPbuffer = c_uint;               // compatible with GLXDrawable
 type GLXPixmap = c_uint;                // compatible with GLXDrawable
 type Pixmap = c_uint;
 type VisualID = c_ulong;
 type Window = c_uint;                   // compatible with Drawable
 type XID = c_uint;
 type XPointer = *mut c_void;
+type XErrorHandler = Option<extern "C" fn(display: *mut Display, event: *mut XErrorEvent) -> c_int>;
+
+// External bindings to XCB and its DRI3 extension, which export and import pixmaps as dma-bufs.
+
+struct XcbConnection;
+
+#[repr(C)]
+struct XcbGenericError {
+    _response_type: u8,
+    error_code: u8,
+    _sequence: u16,
+    _resource_id: u32,
+    _minor_code: u16,
+    _major_code: u8,
+    _pad0: u8,
+    _pad: [u32, ..5],
+    _full_sequence: u32,
+}
+
+#[repr(C)]
+struct XcbExtension {
+    _name: *const c_char,
+    _global_id: c_int,
+}
+
+#[repr(C)]
+struct XcbQueryExtensionReply {
+    _response_type: u8,
+    _pad0: u8,
+    _sequence: u16,
+    _length: u32,
+    present: u8,
+    _major_opcode: u8,
+    _first_event: u8,
+    _first_error: u8,
+}
+
+// Every XCB request cookie is a sequence number.
+#[repr(C)]
+struct XcbCookie {
+    sequence: c_uint,
+}
+
+#[repr(C)]
+struct XcbDri3QueryVersionReply {
+    _response_type: u8,
+    _pad0: u8,
+    _sequence: u16,
+    _length: u32,
+    _major_version: u32,
+    _minor_version: u32,
+}
+
+#[repr(C)]
+struct XcbDri3BufferFromPixmapReply {
+    _response_type: u8,
+    nfd: u8,
+    _sequence: u16,
+    _length: u32,
+    _size: u32,
+    _width: u16,
+    _height: u16,
+    stride: u16,
+    depth: u8,
+    _bpp: u8,
+    _pad0: [u8, ..12],
+}
+
+#[link(name = "X11-xcb")]
+#[link(name = "xcb")]
+#[link(name = "xcb-dri3")]
+extern {
+    static mut xcb_dri3_id: XcbExtension;
+
+    fn XGetXCBConnection(display: *mut Display) -> *mut XcbConnection;
+    fn xcb_get_extension_data(c: *mut XcbConnection, ext: *mut XcbExtension)
+                              -> *const XcbQueryExtensionReply;
+    fn xcb_generate_id(c: *mut XcbConnection) -> u32;
+    fn xcb_request_check(c: *mut XcbConnection, cookie: XcbCookie) -> *mut XcbGenericError;
+    fn xcb_dri3_query_version(c: *mut XcbConnection, major_version: u32, minor_version: u32)
+                              -> XcbCookie;
+    fn xcb_dri3_query_version_reply(c: *mut XcbConnection,
+                                    cookie: XcbCookie,
+                                    e: *mut *mut XcbGenericError)
+                                    -> *mut XcbDri3QueryVersionReply;
+    fn xcb_dri3_buffer_from_pixmap(c: *mut XcbConnection, pixmap: Pixmap) -> XcbCookie;
+    fn xcb_dri3_buffer_from_pixmap_reply(c: *mut XcbConnection,
+                                         cookie: XcbCookie,
+                                         e: *mut *mut XcbGenericError)
+                                         -> *mut XcbDri3BufferFromPixmapReply;
+    fn xcb_dri3_buffer_from_pixmap_reply_fds(c: *mut XcbConnection,
+                                             reply: *mut XcbDri3BufferFromPixmapReply)
+                                             -> *mut c_int;
+    fn xcb_dri3_pixmap_from_buffer_checked(c: *mut XcbConnection,
+                                           pixmap: Pixmap,
+                                           drawable: Drawable,
+                                           size: u32,
+                                           width: u16,
+                                           height: u16,
+                                           stride: u16,
+                                           depth: u8,
+                                           bpp: u8,
+                                           pixmap_fd: c_int)
+                                           -> XcbCookie;
+}
+
+type GlXCreateContextAttribsARB = extern "C" fn(dpy: *mut Display,
+                                                config: GLXFBConfig,
+                                                share_context: GLXContext,
+                                                direct: Bool,
+                                                attrib_list: *const c_int)
+                                                -> GLXContext;
 
 #[link(name = "X11")]
 #[link(name = "GL")]
 extern {
-    fn XOpenDisplay(n: c_int) -> *mut Display;
+    fn XOpenDisplay(display_name: *const c_char) -> *mut Display;
+    fn XCloseDisplay(display: *mut Display) -> c_int;
+    fn XInitThreads() -> c_int;
     fn XCreatePixmap(display: *mut Display, d: Drawable, width: c_uint, height: c_uint, depth: c_uint)
                      -> Pixmap;
+    fn XFreePixmap(display: *mut Display, pixmap: Pixmap) -> c_int;
+    fn XFree(data: *mut c_void) -> c_int;
+    fn XSync(display: *mut Display, discard: Bool) -> c_int;
+    fn XSetErrorHandler(handler: XErrorHandler) -> XErrorHandler;
 
-    fn glXChooseVisual(dpy: *mut Display, screen: c_int, attribList: *mut c_int) -> *mut XVisualInfo;
-    fn glXCreateContext(dpy: *mut Display, vis: *mut XVisualInfo, shareList: GLXContext, direct: Bool)
-                        -> GLXContext;
-    fn glXCreateGLXPixmap(dpy: *mut Display, vis: *mut XVisualInfo, pixmap: Pixmap) -> GLXPixmap;
+    fn glXQueryVersion(dpy: *mut Display, major: *mut c_int, minor: *mut c_int) -> Bool;
+    fn glXQueryExtensionsString(dpy: *mut Display, screen: c_int) -> *const c_char;
+    fn glXGetProcAddress(procName: *const c_char) -> *const c_void;
+    fn glXChooseFBConfig(dpy: *mut Display,
+                         screen: c_int,
+                         attribList: *const c_int,
+                         nitems: *mut c_int)
+                         -> *mut GLXFBConfig;
+    fn glXGetFBConfigAttrib(dpy: *mut Display,
+                            config: GLXFBConfig,
+                            attribute: c_int,
+                            value: *mut c_int)
+                            -> c_int;
+    fn glXGetVisualFromFBConfig(dpy: *mut Display, config: GLXFBConfig) -> *mut XVisualInfo;
+    fn glXCreateNewContext(dpy: *mut Display,
+                           config: GLXFBConfig,
+                           renderType: c_int,
+                           shareList: GLXContext,
+                           direct: Bool)
+                           -> GLXContext;
+    fn glXCreatePbuffer(dpy: *mut Display, config: GLXFBConfig, attribList: *const c_int)
+                        -> GLXPbuffer;
+    fn glXCreatePixmap(dpy: *mut Display,
+                       config: GLXFBConfig,
+                       pixmap: Pixmap,
+                       attribList: *const c_int)
+                       -> GLXPixmap;
+    fn glXDestroyContext(dpy: *mut Display, ctx: GLXContext);
+    fn glXDestroyPbuffer(dpy: *mut Display, pbuf: GLXPbuffer);
+    fn glXDestroyPixmap(dpy: *mut Display, pixmap: GLXPixmap);
+    fn glXGetCurrentContext() -> GLXContext;
+    fn glXGetCurrentDisplay() -> *mut Display;
+    fn glXGetCurrentDrawable() -> GLXDrawable;
+    fn glXGetCurrentReadDrawable() -> GLXDrawable;
+    fn glXQueryContext(dpy: *mut Display, ctx: GLXContext, attribute: c_int, value: *mut c_int)
+                       -> c_int;
     fn glXMakeContextCurrent(dpy: *mut Display, draw: GLXDrawable, read: GLXDrawable, ctx: GLXContext)
                              -> Bool;
 }
 
+// Returns true if the given space-separated extension string contains the given extension.
+fn has_extension(extensions: *const c_char, name: &str) -> bool {
+    if extensions == ptr::null() {
+        return false
+    }
+    let extensions = unsafe { CString::new(extensions, false) };
+    match extensions.as_str() {
+        Some(extensions) => extensions.split(' ').any(|extension| extension == name),
+        None => false,
+    }
+}
+
+// X errors
+
+// Serializes `trap_x_errors`, since the error handler is global to the process.
+static mut x_error_lock: *mut Mutex<()> = 0 as *mut Mutex<()>;
+static mut x_error_lock_initialized: Once = ONCE_INIT;
+
+// Set by `record_x_error` when an error arrives while errors are trapped.
+static mut x_error_received: AtomicBool = INIT_ATOMIC_BOOL;
+
+extern "C" fn record_x_error(_: *mut Display, _: *mut XErrorEvent) -> c_int {
+    unsafe {
+        x_error_received.store(true, SeqCst);
+    }
+    0
+}
+
+// Runs the given function, which makes requests on the display, and returns its result along with
+// whether the server reported an error for any of them. Xlib reports errors asynchronously and its
+// default handler exits the process, so this installs a handler of its own and waits for the server
+// to process the requests before restoring the previous one.
+fn trap_x_errors<T>(display: *mut Display, f: || -> T) -> (T, bool) {
+    unsafe {
+        x_error_lock_initialized.doit(|| {
+            x_error_lock = mem::transmute(box Mutex::new(()));
+        });
+        let _guard = (*x_error_lock).lock();
+
+        // Errors from earlier requests belong to whoever made them.
+        XSync(display, 0);
+        x_error_received.store(false, SeqCst);
+        let previous_handler = XSetErrorHandler(Some(record_x_error));
+        let result = f();
+        XSync(display, 0);
+        XSetErrorHandler(previous_handler);
+        (result, x_error_received.load(SeqCst))
+    }
+}
+
 // X11 macros
 
 fn DefaultScreen(dpy: *mut Display) -> c_int {
@@ -167,96 +424,946 @@ fn ScreenOfDisplay(dpy: *mut Display, scr: c_int) -> *mut Screen {
     }
 }
 
+// DRI3
+
+// Frees the error an XCB reply function stored, if any, and returns its code.
+fn take_xcb_error(error: *mut XcbGenericError) -> Option<u8> {
+    if error == ptr::mut_null() {
+        return None
+    }
+    unsafe {
+        let code = (*error).error_code;
+        free(error as *mut c_void);
+        Some(code)
+    }
+}
+
+// Returns the XCB connection underlying the display if the server supports DRI3. The extension is
+// looked up before any DRI3 request is sent, since servers without it, such as Xvfb, treat the
+// request as a protocol error.
+fn dri3_connection(display: *mut Display) -> Result<*mut XcbConnection,Error> {
+    unsafe {
+        let connection = XGetXCBConnection(display);
+        let extension = xcb_get_extension_data(connection, &mut xcb_dri3_id);
+        if extension == ptr::null() || (*extension).present == 0 {
+            return Err(ExtensionUnsupported("DRI3"))
+        }
+
+        let cookie = xcb_dri3_query_version(connection, 1, 0);
+        let mut error = ptr::mut_null();
+        let reply = xcb_dri3_query_version_reply(connection, cookie, &mut error);
+        match take_xcb_error(error) {
+            Some(code) => debug!("DRI3 version query failed with X error {}", code),
+            None => {}
+        }
+        if reply == ptr::mut_null() {
+            return Err(ExtensionUnsupported("DRI3"))
+        }
+        free(reply as *mut c_void);
+        Ok(connection)
+    }
+}
+
+// Exports the given pixmap as a dma-buf, returning the buffer together with the file descriptor
+// it names.
+fn export_pixmap(display: *mut Display, pixmap: Pixmap)
+                 -> Result<(DmaBuf, FileDescriptor),Error> {
+    let connection = try!(dri3_connection(display));
+    unsafe {
+        let cookie = xcb_dri3_buffer_from_pixmap(connection, pixmap);
+        let mut error = ptr::mut_null();
+        let reply = xcb_dri3_buffer_from_pixmap_reply(connection, cookie, &mut error);
+        match take_xcb_error(error) {
+            Some(code) => debug!("DRI3 could not export pixmap {}: X error {}", pixmap, code),
+            None => {}
+        }
+        if reply == ptr::mut_null() {
+            return Err(SurfaceCreationFailed)
+        }
+        let (nfd, stride, depth) = ((*reply).nfd, (*reply).stride, (*reply).depth);
+        let fd = if nfd == 1 {
+            Some(FileDescriptor::from_raw_fd(
+                *xcb_dri3_buffer_from_pixmap_reply_fds(connection, reply) as int))
+        } else {
+            None
+        };
+        free(reply as *mut c_void);
+
+        let fd = match fd {
+            Some(fd) => fd,
+            None => {
+                debug!("DRI3 exported pixmap {} with {} file descriptors", pixmap, nfd);
+                return Err(SurfaceCreationFailed)
+            }
+        };
+        let buffer = DmaBuf {
+            fourcc: if depth == 32 { DRM_FORMAT_ARGB8888 } else { DRM_FORMAT_XRGB8888 },
+            modifier: DRM_FORMAT_MOD_INVALID,
+            planes: vec!(DmaBufPlane {
+                fd: fd.as_raw_fd(),
+                offset: 0,
+                stride: stride as u32,
+            }),
+        };
+        Ok((buffer, fd))
+    }
+}
+
+// Imports a single-plane 32-bit dma-buf into a new pixmap on the given display.
+fn import_dma_buf(display: *mut Display, buffer: &DmaBuf, size: Size2D<int>)
+                  -> Result<Pixmap,Error> {
+    let depth = match buffer.fourcc {
+        DRM_FORMAT_XRGB8888 => 24,
+        DRM_FORMAT_ARGB8888 => 32,
+        _ => return Err(IncompatibleSurface),
+    };
+    if buffer.planes.len() != 1 || buffer.modifier != DRM_FORMAT_MOD_INVALID {
+        return Err(IncompatibleSurface)
+    }
+    let plane = &buffer.planes[0];
+    let connection = try!(dri3_connection(display));
+    unsafe {
+        // The server takes ownership of the descriptor it is sent, so send a duplicate.
+        let fd = dup(plane.fd as c_int);
+        if fd < 0 {
+            return Err(SurfaceCreationFailed)
+        }
+        let pixmap = xcb_generate_id(connection);
+        let cookie = xcb_dri3_pixmap_from_buffer_checked(connection,
+                                                         pixmap,
+                                                         RootWindow(display,
+                                                                    DefaultScreen(display)),
+                                                         plane.stride * size.height as u32,
+                                                         size.width as u16,
+                                                         size.height as u16,
+                                                         plane.stride as u16,
+                                                         depth,
+                                                         32,
+                                                         fd);
+        match take_xcb_error(xcb_request_check(connection, cookie)) {
+            Some(code) => {
+                debug!("DRI3 could not import the dma-buf into a pixmap: X error {}", code);
+                Err(SurfaceCreationFailed)
+            }
+            None => Ok(pixmap),
+        }
+    }
+}
+
 // Implementation
 
+/// A connection to an X server, shared by every context created on it. The connection is closed
+/// when the last context using it goes away, unless the embedder owns it.
+struct DisplayConnection {
+    display: *mut Display,
+    owned: bool,
+    _resource: TrackedResource,
+}
+
+impl Drop for DisplayConnection {
+    fn drop(&mut self) {
+        if self.owned {
+            debug!("closing X connection {}", self.display);
+            unsafe {
+                XCloseDisplay(self.display);
+            }
+        }
+    }
+}
+
+// The connection to the display named by `$DISPLAY`, shared by every context on this task that
+// was created without an explicit display.
+local_data_key!(default_display_connection: Weak<DisplayConnection>)
+
+// Set once Xlib has been made thread-safe.
+static mut xlib_threads_initialized: Once = ONCE_INIT;
+
+/// Makes Xlib safe to call from several threads, which contexts handed off with `ContextHandoff`
+/// and contexts sharing a connection across threads require. Xlib only honors this before any
+/// other Xlib call has been made in the process, so call it first thing in `main()`, before this
+/// crate or the embedder's windowing layer opens a display. Calling it again does nothing.
+pub fn init_xlib_threads() {
+    unsafe {
+        xlib_threads_initialized.doit(|| {
+            if XInitThreads() == 0 {
+                debug!("XInitThreads failed; X connections must stay on one thread");
+            }
+        });
+    }
+}
+
+/// The drawable a GLX context renders into.
+enum Surface {
+    /// A pbuffer, which lives only on the server.
+    PbufferSurface(GLXPbuffer),
+    /// An X pixmap, which other clients can attach to by its XID, and the GLX pixmap wrapping it.
+    PixmapSurface(Pixmap, GLXPixmap),
+    /// The draw and read drawables a foreign context was current on, which belong to whoever
+    /// created them.
+    ForeignSurface(GLXDrawable, GLXDrawable),
+}
+
+impl Surface {
+    // Returns the drawables to draw into and read from.
+    fn drawables(&self) -> (GLXDrawable, GLXDrawable) {
+        match *self {
+            PbufferSurface(pbuffer) => (pbuffer, pbuffer),
+            PixmapSurface(_, glx_pixmap) => (glx_pixmap, glx_pixmap),
+            ForeignSurface(draw, read) => (draw, read),
+        }
+    }
+
+    fn destroy(&self, display: *mut Display) {
+        unsafe {
+            match *self {
+                PbufferSurface(pbuffer) => glXDestroyPbuffer(display, pbuffer),
+                PixmapSurface(pixmap, glx_pixmap) => {
+                    glXDestroyPixmap(display, glx_pixmap);
+                    XFreePixmap(display, pixmap);
+                }
+                ForeignSurface(..) => {}
+            }
+        }
+    }
+}
+
+/// The kind of drawable a context needs.
+#[deriving(PartialEq)]
+enum SurfaceKind {
+    /// A pbuffer, or a pixmap if pbuffers are unsupported.
+    PreferPbuffer,
+    /// A pixmap, so that other processes can attach to it.
+    RequirePixmap,
+}
+
+/// The X and GLX objects backing a context. These are destroyed when the last clone of the
+/// context goes away.
+struct NativeResources {
+    display: Arc<DisplayConnection>,
+    // Replaced when a share context is resized.
+    surface: Mutex<Surface>,
+    pixel_format: PixelFormat,
+    // The GLX context, if we created it. Wrapped contexts are owned by whoever created them.
+    owned_context: Option<GLXContext>,
+    _drawable_resource: TrackedResource,
+    _context_resource: Option<TrackedResource>,
+}
+
+impl Drop for NativeResources {
+    fn drop(&mut self) {
+        let display = self.display.display;
+        unsafe {
+            match self.owned_context {
+                Some(context) => {
+                    if glXGetCurrentContext() == context {
+                        glXMakeContextCurrent(display, 0, 0, ptr::mut_null());
+                    }
+                    glXDestroyContext(display, context);
+                }
+                None => {}
+            }
+        }
+        self.surface.lock().destroy(display);
+    }
+}
+
 /// Linux-specific interface to 3D graphics contexts.
+///
+/// Clones share the same X connection, drawable and GLX context; these are released when the last
+/// clone is dropped.
 pub struct GraphicsContext {
-    display: *mut Display,
-    pixmap: GLXPixmap,
+    resources: Arc<NativeResources>,
     context: Arc<GLXContext>,
+    // A context can be sent to another thread but not used from two threads at once.
+    _no_sync: marker::NoSync,
 }
 
 impl GraphicsContext {
-    // Creates a new, possibly shared, GLX context.
-    fn new_possibly_shared(share_context: Option<GraphicsContext>) -> GraphicsContext {
-        let (display, visual, pixmap) = GraphicsContext::create_display_visual_and_pixmap();
+    /// Returns a builder that can create contexts on a specific X display.
+    pub fn builder() -> GraphicsContextBuilder {
+        GraphicsContextBuilder::new()
+    }
+
+    /// Returns the X connection this context renders through.
+    pub fn display(&self) -> *mut Display {
+        self.resources.display.display
+    }
+
+    // Creates a new, possibly shared, GLX context rendering to a drawable of the given kind and
+    // size.
+    fn new_possibly_shared(builder: &GraphicsContextBuilder,
+                           share_context: Option<&GraphicsContext>,
+                           kind: SurfaceKind,
+                           size: Size2D<int>)
+                           -> Result<GraphicsContext,Error> {
+        // Shared contexts must live on the same connection as the context they share with.
+        let display = match share_context {
+            None => try!(builder.open_display()),
+            Some(ref share_context) => share_context.resources.display.clone(),
+        };
+        let pixel_format = &builder.attributes.pixel_format;
+        let (config, surface) =
+            try!(GraphicsContext::create_surface(display.display, pixel_format, kind, size));
 
         unsafe {
-            let context = match share_context {
-                None => glXCreateContext(display, visual, ptr::mut_null(), 1),
-                Some(share_context) => {
-                    let native_share_context = share_context.native();
-                    glXCreateContext(display, visual, *native_share_context, 1)
-                }
+            // Build the resources first, so that the drawable is freed if context creation failed.
+            let mut resources = NativeResources {
+                display: display.clone(),
+                surface: Mutex::new(surface),
+                pixel_format: GraphicsContext::granted_pixel_format(display.display, config),
+                owned_context: None,
+                _drawable_resource: TrackedResource::new(DrawableResource),
+                _context_resource: None,
             };
 
-            assert!(context != ptr::mut_null());
+            let native_share_context = match share_context {
+                None => ptr::mut_null(),
+                Some(share_context) => *share_context.native(),
+            };
+            let context = try!(GraphicsContext::create_context(display.display,
+                                                               config,
+                                                               native_share_context,
+                                                               &builder.attributes));
+            resources.owned_context = Some(context);
+            resources._context_resource = Some(TrackedResource::new(ContextResource));
 
-            GraphicsContext {
-                display: display,
-                pixmap: pixmap,
+            Ok(GraphicsContext {
+                resources: Arc::new(resources),
                 context: Arc::new(context),
+                _no_sync: marker::NoSync,
+            })
+        }
+    }
+
+    // Creates a GLX context with the given attributes. Specific versions, profiles and flags need
+    // `GLX_ARB_create_context`, and profiles other than the default also need
+    // `GLX_ARB_create_context_profile`; everything else goes through `glXCreateNewContext`.
+    fn create_context(display: *mut Display,
+                      config: GLXFBConfig,
+                      share_context: GLXContext,
+                      attributes: &ContextAttributes)
+                      -> Result<GLXContext,Error> {
+        if attributes.is_legacy() {
+            let context = unsafe {
+                glXCreateNewContext(display, config, GLX_RGBA_TYPE, share_context, 1)
+            };
+            if context == ptr::mut_null() {
+                return Err(ContextCreationFailed)
+            }
+            return Ok(context)
+        }
+
+        let extensions = unsafe { glXQueryExtensionsString(display, DefaultScreen(display)) };
+        if !has_extension(extensions, "GLX_ARB_create_context") {
+            return Err(ExtensionUnsupported("GLX_ARB_create_context"))
+        }
+        let profile_mask = match attributes.api {
+            OpenGLES => {
+                if !has_extension(extensions, "GLX_EXT_create_context_es2_profile") {
+                    return Err(ExtensionUnsupported("GLX_EXT_create_context_es2_profile"))
+                }
+                Some(GLX_CONTEXT_ES2_PROFILE_BIT_EXT)
             }
+            OpenGL if attributes.wants_core_profile() => Some(GLX_CONTEXT_CORE_PROFILE_BIT_ARB),
+            OpenGL => Some(GLX_CONTEXT_COMPATIBILITY_PROFILE_BIT_ARB),
+        };
+        // Without the profile extension the server rejects the profile attribute, and every
+        // context gets the default profile, which is the compatibility profile.
+        let profile_mask = if has_extension(extensions, "GLX_ARB_create_context_profile") {
+            profile_mask
+        } else if profile_mask == Some(GLX_CONTEXT_COMPATIBILITY_PROFILE_BIT_ARB) {
+            None
+        } else {
+            return Err(ExtensionUnsupported("GLX_ARB_create_context_profile"))
+        };
+        let address = "glXCreateContextAttribsARB".with_c_str(|name| unsafe {
+            glXGetProcAddress(name)
+        });
+        if address == ptr::null() {
+            return Err(ExtensionUnsupported("GLX_ARB_create_context"))
         }
+        let create_context_attribs: GlXCreateContextAttribsARB = unsafe {
+            mem::transmute(address)
+        };
+
+        let (major_version, minor_version) = match attributes.version {
+            Some(version) => version,
+            None if attributes.api == OpenGLES => (2, 0),
+            None => (1, 0),
+        };
+        let mut flags = 0;
+        if attributes.debug {
+            flags |= GLX_CONTEXT_DEBUG_BIT_ARB;
+        }
+        if attributes.forward_compatible {
+            flags |= GLX_CONTEXT_FORWARD_COMPATIBLE_BIT_ARB;
+        }
+        let mut context_attributes = vec!(
+            GLX_CONTEXT_MAJOR_VERSION_ARB, major_version as c_int,
+            GLX_CONTEXT_MINOR_VERSION_ARB, minor_version as c_int,
+            GLX_CONTEXT_FLAGS_ARB, flags,
+        );
+        match profile_mask {
+            Some(profile_mask) => {
+                context_attributes.push_all([ GLX_CONTEXT_PROFILE_MASK_ARB, profile_mask ]);
+            }
+            None => {}
+        }
+        context_attributes.push(0);
+
+        // Unsupported versions and flags are reported as X errors rather than a null context.
+        let (context, failed) = trap_x_errors(display, || {
+            create_context_attribs(display, config, share_context, 1, context_attributes.as_ptr())
+        });
+        if failed || context == ptr::mut_null() {
+            debug!("glXCreateContextAttribsARB failed for {}", attributes);
+            if context != ptr::mut_null() {
+                unsafe {
+                    glXDestroyContext(display, context);
+                }
+            }
+            return Err(ContextCreationFailed)
+        }
+        Ok(context)
     }
 
-    fn create_display_visual_and_pixmap() -> (*mut Display, *mut XVisualInfo, GLXPixmap) {
-        unsafe {
-            // Get a connection.
-            let display = XOpenDisplay(0);
+    // Creates a drawable of the given kind and size from the config the context was created with,
+    // so that the context can be made current on it.
+    fn new_surface(&self, kind: SurfaceKind, size: Size2D<int>) -> Result<Surface,Error> {
+        let display = self.display();
+        let config = try!(GraphicsContext::context_fb_config(display, *self.context));
+        GraphicsContext::create_surface_with_config(display, config, kind, size)
+    }
 
-            // Get an appropriate visual.
-            let visual = glXChooseVisual(display, DefaultScreen(display), &mut ATTRIBUTES[0]);
+    // Makes the context current on the given drawable and destroys the old one. If the context
+    // cannot be made current, the new drawable is destroyed instead and the old one kept.
+    fn replace_surface(&self, surface: Surface) -> Result<(),Error> {
+        let display = self.display();
+        let old_surface = mem::replace(&mut *self.resources.surface.lock(), surface);
+        match self.try_make_current() {
+            Ok(()) => {
+                old_surface.destroy(display);
+                Ok(())
+            }
+            Err(error) => {
+                let surface = mem::replace(&mut *self.resources.surface.lock(), old_surface);
+                surface.destroy(display);
+                Err(error)
+            }
+        }
+    }
+
+    // Creates a drawable of the given kind and size, and returns it along with the config it was
+    // created with. Pbuffer configs that also support pixmaps are preferred, so that readers can
+    // attach shared pixmaps to the context.
+    fn create_surface(display: *mut Display,
+                      pixel_format: &PixelFormat,
+                      kind: SurfaceKind,
+                      size: Size2D<int>)
+                      -> Result<(GLXFBConfig, Surface),Error> {
+        try!(GraphicsContext::check_glx_version(display));
+
+        if kind == PreferPbuffer {
+            let drawable_types = GLX_PBUFFER_BIT | GLX_PIXMAP_BIT;
+            let config = GraphicsContext::choose_fb_config(display, pixel_format, drawable_types)
+                .or_else(|_| {
+                    GraphicsContext::choose_fb_config(display, pixel_format, GLX_PBUFFER_BIT)
+                });
+            match config {
+                Ok(config) => {
+                    match GraphicsContext::create_pbuffer(display, config, size) {
+                        Ok(surface) => return Ok((config, surface)),
+                        Err(_) => debug!("glXCreatePbuffer failed; falling back to a pixmap"),
+                    }
+                }
+                Err(_) => debug!("no config supports pbuffers; falling back to a pixmap"),
+            }
+        }
+
+        let config = try!(GraphicsContext::choose_fb_config(display,
+                                                            pixel_format,
+                                                            GLX_PIXMAP_BIT));
+        let surface = try!(GraphicsContext::create_pixmap(display, config, size));
+        Ok((config, surface))
+    }
+
+    // Creates a drawable of the given kind and size with the given config, such as the one an
+    // existing context was created with.
+    fn create_surface_with_config(display: *mut Display,
+                                  config: GLXFBConfig,
+                                  kind: SurfaceKind,
+                                  size: Size2D<int>)
+                                  -> Result<Surface,Error> {
+        let drawable_type =
+            GraphicsContext::get_fb_config_attrib(display, config, GLX_DRAWABLE_TYPE);
+        if kind == PreferPbuffer && (drawable_type & GLX_PBUFFER_BIT) != 0 {
+            match GraphicsContext::create_pbuffer(display, config, size) {
+                Ok(surface) => return Ok(surface),
+                Err(_) => debug!("glXCreatePbuffer failed; falling back to a pixmap"),
+            }
+        }
+        if (drawable_type & GLX_PIXMAP_BIT) == 0 {
+            debug!("the config supports neither a usable pbuffer nor pixmaps");
+            return Err(NoMatchingVisual)
+        }
+        GraphicsContext::create_pixmap(display, config, size)
+    }
+
+    fn create_pbuffer(display: *mut Display, config: GLXFBConfig, size: Size2D<int>)
+                      -> Result<Surface,Error> {
+        let attributes = [
+            GLX_PBUFFER_WIDTH, size.width as c_int,
+            GLX_PBUFFER_HEIGHT, size.height as c_int,
+            GLX_PRESERVED_CONTENTS, 1,
+            0,
+        ];
+        // Servers without pbuffer support fail the request rather than returning no drawable.
+        let (pbuffer, failed) = trap_x_errors(display, || unsafe {
+            glXCreatePbuffer(display, config, &attributes[0])
+        });
+        debug!("glXCreatePbuffer returned {}", pbuffer);
+        if failed || pbuffer == 0 {
+            if pbuffer != 0 {
+                trap_x_errors(display, || unsafe { glXDestroyPbuffer(display, pbuffer) });
+            }
+            return Err(SurfaceCreationFailed)
+        }
+        Ok(PbufferSurface(pbuffer))
+    }
+
+    fn create_pixmap(display: *mut Display, config: GLXFBConfig, size: Size2D<int>)
+                     -> Result<Surface,Error> {
+        unsafe {
+            let visual = glXGetVisualFromFBConfig(display, config);
+            if visual == ptr::mut_null() {
+                return Err(NoMatchingVisual)
+            }
+            let depth = (*visual).depth;
+            XFree(visual as *mut c_void);
 
             // Create the pixmap.
             let root_window = RootWindow(display, DefaultScreen(display));
-            let pixmap = XCreatePixmap(display, root_window, 10, 10, 24);
-            let glx_pixmap = glXCreateGLXPixmap(display, visual, pixmap);
+            let ((pixmap, glx_pixmap), failed) = trap_x_errors(display, || {
+                let pixmap = XCreatePixmap(display,
+                                           root_window,
+                                           size.width as c_uint,
+                                           size.height as c_uint,
+                                           depth as c_uint);
+                let glx_pixmap = if pixmap == 0 {
+                    0
+                } else {
+                    glXCreatePixmap(display, config, pixmap, ptr::null())
+                };
+                (pixmap, glx_pixmap)
+            });
 
-            debug!("XCreatePixmap returned {}, glXCreateGLXPixmap returned {}",
-                   pixmap,
-                   glx_pixmap);
+            debug!("XCreatePixmap returned {}, glXCreatePixmap returned {}", pixmap, glx_pixmap);
+
+            if failed || glx_pixmap == 0 {
+                trap_x_errors(display, || {
+                    if glx_pixmap != 0 {
+                        glXDestroyPixmap(display, glx_pixmap);
+                    }
+                    if pixmap != 0 {
+                        XFreePixmap(display, pixmap);
+                    }
+                });
+                return Err(SurfaceCreationFailed)
+            }
 
-            (display, visual, glx_pixmap)
+            Ok(PixmapSurface(pixmap, glx_pixmap))
         }
     }
+
+    // Returns the config the given context was created with.
+    fn context_fb_config(display: *mut Display, context: GLXContext)
+                         -> Result<GLXFBConfig,Error> {
+        unsafe {
+            let mut id = 0;
+            if glXQueryContext(display, context, GLX_FBCONFIG_ID, &mut id) != 0 {
+                debug!("glXQueryContext could not find the config of context {}", context);
+                return Err(NoMatchingVisual)
+            }
+            let attributes = [ GLX_FBCONFIG_ID, id, 0 ];
+            let mut config_count = 0;
+            let configs = glXChooseFBConfig(display,
+                                            DefaultScreen(display),
+                                            attributes.as_ptr(),
+                                            &mut config_count);
+            if configs == ptr::mut_null() || config_count == 0 {
+                debug!("glXChooseFBConfig found no config with ID {}", id);
+                return Err(NoMatchingVisual)
+            }
+            let config = *configs;
+            XFree(configs as *mut c_void);
+            Ok(config)
+        }
+    }
+
+    // Fails unless the server supports GLX 1.3, which introduced FBConfigs and pbuffers.
+    fn check_glx_version(display: *mut Display) -> Result<(),Error> {
+        let (mut major, mut minor) = (0, 0);
+        unsafe {
+            if glXQueryVersion(display, &mut major, &mut minor) == 0 {
+                return Err(ExtensionUnsupported("GLX"))
+            }
+        }
+        if major < 1 || (major == 1 && minor < 3) {
+            debug!("the server only supports GLX {}.{}", major, minor);
+            return Err(ExtensionUnsupported("GLX 1.3"))
+        }
+        Ok(())
+    }
+
+    // Returns the config that best matches the given pixel format and supports the given
+    // drawable types.
+    fn choose_fb_config(display: *mut Display, pixel_format: &PixelFormat, drawable_type: c_int)
+                        -> Result<GLXFBConfig,Error> {
+        let mut attributes = vec!(
+            GLX_DRAWABLE_TYPE, drawable_type,
+            GLX_RENDER_TYPE, GLX_RGBA_BIT,
+            GLX_RED_SIZE, pixel_format.red_bits as c_int,
+            GLX_GREEN_SIZE, pixel_format.green_bits as c_int,
+            GLX_BLUE_SIZE, pixel_format.blue_bits as c_int,
+            GLX_ALPHA_SIZE, pixel_format.alpha_bits as c_int,
+            GLX_DEPTH_SIZE, pixel_format.depth_bits as c_int,
+            GLX_STENCIL_SIZE, pixel_format.stencil_bits as c_int,
+            GLX_DOUBLEBUFFER, pixel_format.double_buffered as c_int,
+        );
+        if pixel_format.samples > 0 {
+            attributes.push_all([
+                GLX_SAMPLE_BUFFERS, 1,
+                GLX_SAMPLES, pixel_format.samples as c_int,
+            ]);
+        }
+        if pixel_format.srgb {
+            attributes.push_all([ GLX_FRAMEBUFFER_SRGB_CAPABLE_ARB, 1 ]);
+        }
+        attributes.push(0);
+
+        unsafe {
+            let mut config_count = 0;
+            let configs = glXChooseFBConfig(display,
+                                            DefaultScreen(display),
+                                            attributes.as_ptr(),
+                                            &mut config_count);
+            if configs == ptr::mut_null() || config_count == 0 {
+                debug!("glXChooseFBConfig found no config for {}", pixel_format);
+                return Err(NoMatchingVisual)
+            }
+            let config = *configs;
+            XFree(configs as *mut c_void);
+            Ok(config)
+        }
+    }
+
+    // Returns the value of an attribute of the given config, or zero if it has none.
+    fn get_fb_config_attrib(display: *mut Display, config: GLXFBConfig, attribute: c_int)
+                            -> c_int {
+        let mut value = 0;
+        unsafe {
+            if glXGetFBConfigAttrib(display, config, attribute, &mut value) != 0 {
+                return 0
+            }
+        }
+        value
+    }
+
+    // Returns the pixel format of the given config.
+    fn granted_pixel_format(display: *mut Display, config: GLXFBConfig) -> PixelFormat {
+        let get = |attribute: c_int| -> c_int {
+            GraphicsContext::get_fb_config_attrib(display, config, attribute)
+        };
+        PixelFormat {
+            red_bits: get(GLX_RED_SIZE) as u8,
+            green_bits: get(GLX_GREEN_SIZE) as u8,
+            blue_bits: get(GLX_BLUE_SIZE) as u8,
+            alpha_bits: get(GLX_ALPHA_SIZE) as u8,
+            depth_bits: get(GLX_DEPTH_SIZE) as u8,
+            stencil_bits: get(GLX_STENCIL_SIZE) as u8,
+            samples: if get(GLX_SAMPLE_BUFFERS) != 0 { get(GLX_SAMPLES) as u8 } else { 0 },
+            srgb: get(GLX_FRAMEBUFFER_SRGB_CAPABLE_ARB) != 0,
+            double_buffered: get(GLX_DOUBLEBUFFER) != 0,
+        }
+    }
+}
+
+/// Where a `GraphicsContextBuilder` gets its X connection from.
+enum DisplaySource {
+    /// Use the connection to the display named by `$DISPLAY`, opening it if necessary.
+    DefaultDisplay,
+    /// Open a new connection to the display with the given name, for example `:1`.
+    NamedDisplay(String),
+    /// Use a connection that the embedder has already opened.
+    ExistingDisplay(*mut Display),
+}
+
+/// Creates Linux graphics contexts with non-default settings, such as an explicit X display.
+pub struct GraphicsContextBuilder {
+    display: DisplaySource,
+    attributes: ContextAttributes,
+    size: Size2D<int>,
 }
 
+impl GraphicsContextBuilder {
+    /// Returns a builder that connects to the display named by `$DISPLAY`.
+    pub fn new() -> GraphicsContextBuilder {
+        GraphicsContextBuilder {
+            display: DefaultDisplay,
+            attributes: Default::default(),
+            size: Size2D(1, 1),
+        }
+    }
+
+    /// Connects to the X display with the given name instead of the one named by `$DISPLAY`.
+    pub fn display_name(self, name: &str) -> GraphicsContextBuilder {
+        GraphicsContextBuilder {
+            display: NamedDisplay(name.to_string()),
+            ..self
+        }
+    }
+
+    /// Uses an X connection owned by the embedder, such as the one belonging to the windowing
+    /// layer. The connection must stay open for as long as any context built on it is alive; it is
+    /// never closed by this crate. If contexts built on it will be handed off to other threads,
+    /// the embedder must have called `init_xlib_threads()` before opening it.
+    pub fn display(self, display: *mut Display) -> GraphicsContextBuilder {
+        GraphicsContextBuilder {
+            display: ExistingDisplay(display),
+            ..self
+        }
+    }
+
+    /// Requests the given attributes, such as the pixel format, for the contexts this builder
+    /// creates.
+    pub fn attributes(self, attributes: ContextAttributes) -> GraphicsContextBuilder {
+        GraphicsContextBuilder {
+            attributes: attributes,
+            ..self
+        }
+    }
+
+    /// Sets the size of the drawable that contexts built by `build()` and `build_shared()` render
+    /// into when no framebuffer object is bound. The default is 1x1.
+    pub fn size(self, size: Size2D<int>) -> GraphicsContextBuilder {
+        GraphicsContextBuilder {
+            size: size,
+            ..self
+        }
+    }
+
+    /// Creates a new offscreen 3D graphics context rendering into a pbuffer, or into a pixmap if
+    /// the server does not support pbuffers.
+    pub fn build(&self) -> Result<GraphicsContext,Error> {
+        GraphicsContext::new_possibly_shared(self, None, PreferPbuffer, self.size)
+    }
+
+    /// Creates a new offscreen 3D graphics context shared with the given context. The new context
+    /// uses the same X connection as the share context.
+    pub fn build_shared(&self, share_context: &GraphicsContext) -> Result<GraphicsContext,Error> {
+        GraphicsContext::new_possibly_shared(self, Some(share_context), PreferPbuffer, self.size)
+    }
+
+    /// Creates a new share context whose pixmap has the given size.
+    pub fn build_share_context(&self, size: Size2D<int>) -> Result<Context,Error> {
+        let context = try!(GraphicsContext::new_possibly_shared(self, None, RequirePixmap, size));
+        try!(context.try_make_current());
+        let dma_buf = try!(Context::export(context.display(), &*context.resources.surface.lock()));
+
+        Ok(Context {
+            context: context,
+            dma_buf: dma_buf,
+            generation: 0,
+            size: size,
+        })
+    }
+
+    // Returns the X connection to use, opening a new one if necessary.
+    fn open_display(&self) -> Result<Arc<DisplayConnection>,Error> {
+        let display = match self.display {
+            DefaultDisplay => {
+                // Reuse the default connection if another context still holds it.
+                match default_display_connection.get() {
+                    Some(weak) => match weak.upgrade() {
+                        Some(connection) => return Ok(connection),
+                        None => {}
+                    },
+                    None => {}
+                }
+                unsafe { XOpenDisplay(ptr::null()) }
+            }
+            NamedDisplay(ref name) => name.with_c_str(|name| unsafe { XOpenDisplay(name) }),
+            ExistingDisplay(display) => display,
+        };
+
+        if display == ptr::mut_null() {
+            match self.display {
+                NamedDisplay(ref name) => debug!("XOpenDisplay failed to open {}", name),
+                _ => debug!("XOpenDisplay failed to open the default display"),
+            }
+            return Err(NoDisplay)
+        }
+
+        let connection = Arc::new(DisplayConnection {
+            display: display,
+            owned: match self.display {
+                ExistingDisplay(_) => false,
+                _ => true,
+            },
+            _resource: TrackedResource::new(DisplayResource),
+        });
+        match self.display {
+            DefaultDisplay => {
+                default_display_connection.replace(Some(connection.downgrade()));
+            }
+            _ => {}
+        }
+        Ok(connection)
+    }
+}
+
+local_data_key!(current_context: CurrentContext<NativeResources, GLXContext>)
+
 impl GraphicsContextMethods<GLXContext> for GraphicsContext {
     /// Wraps the given instance of the native GLX graphics context, bumping the reference count in
-    /// the process.
+    /// the process. The wrapped context is not destroyed when this `GraphicsContext` goes away.
     fn wrap(instance: Arc<GLXContext>) -> GraphicsContext {
-        let (display, _, pixmap) = GraphicsContext::create_display_visual_and_pixmap();
+        let display = match GraphicsContextBuilder::new().open_display() {
+            Ok(display) => display,
+            Err(error) => fail!("failed to wrap context: {}", error),
+        };
+        // The drawable must come from the context's own config, or it cannot be made current.
+        let config = match GraphicsContext::context_fb_config(display.display, *instance) {
+            Ok(config) => config,
+            Err(error) => fail!("failed to find the config of the wrapped context: {}", error),
+        };
+        let surface = match GraphicsContext::create_surface_with_config(display.display,
+                                                                        config,
+                                                                        PreferPbuffer,
+                                                                        Size2D(1, 1)) {
+            Ok(surface) => surface,
+            Err(error) => fail!("failed to create a drawable for the wrapped context: {}", error),
+        };
         GraphicsContext {
-            display: display,
-            pixmap: pixmap,
+            resources: Arc::new(NativeResources {
+                display: display.clone(),
+                surface: Mutex::new(surface),
+                pixel_format: GraphicsContext::granted_pixel_format(display.display, config),
+                owned_context: None,
+                _drawable_resource: TrackedResource::new(DrawableResource),
+                _context_resource: None,
+            }),
             context: instance.clone(),
+            _no_sync: marker::NoSync,
         }
     }
 
-    /// Returns the underlying native 3D context.
+    /// Returns the underlying native 3D context. The native context is only valid for as long as
+    /// this context or one of its clones is alive.
     fn native(&self) -> Arc<GLXContext> {
         self.context.clone()
     }
 
-    /// Creates a new offscreen 3D graphics context.
-    fn new() -> GraphicsContext {
-        GraphicsContext::new_possibly_shared(None)
+    /// Creates a new offscreen 3D graphics context with the given attributes.
+    fn try_new_with_attributes(attributes: &ContextAttributes) -> Result<GraphicsContext,Error> {
+        GraphicsContextBuilder::new().attributes(attributes.clone()).build()
     }
 
-    /// Creates a new offscreen 3D graphics context shared with the given context.
-    fn new_shared(share_context: GraphicsContext) -> GraphicsContext {
-        GraphicsContext::new_possibly_shared(Some(share_context))
+    /// Creates a new offscreen 3D graphics context with the given attributes, shared with this
+    /// context.
+    fn try_new_shared_with_attributes(&self, attributes: &ContextAttributes)
+                                      -> Result<GraphicsContext,Error> {
+        GraphicsContextBuilder::new().attributes(attributes.clone()).build_shared(self)
     }
 
     /// Makes this context the current context.
-    fn make_current(&self) {
+    fn try_make_current(&self) -> Result<(),Error> {
         unsafe {
-            let result = glXMakeContextCurrent(self.display,
-                                               self.pixmap,
-                                               self.pixmap,
-                                               *self.context);
-            assert!(result != 0);
+            let (draw, read) = self.resources.surface.lock().drawables();
+            let result = glXMakeContextCurrent(self.display(), draw, read, *self.context);
+            if result == 0 {
+                return Err(MakeCurrentFailed)
+            }
+            current_context.replace(Some(CurrentContext::new(&self.resources, &self.context)));
+            Ok(())
+        }
+    }
+
+    fn try_release_current(&self) -> Result<(),Error> {
+        if !self.is_current() {
+            return Ok(())
+        }
+        unsafe {
+            let result = glXMakeContextCurrent(self.display(), 0, 0, ptr::mut_null());
+            if result == 0 {
+                return Err(MakeCurrentFailed)
+            }
+            current_context.replace(None);
+            Ok(())
+        }
+    }
+
+    fn is_current(&self) -> bool {
+        unsafe {
+            glXGetCurrentContext() == *self.context
+        }
+    }
+
+    /// Returns the current context. Contexts this task made current are returned along with their
+    /// resources; any other GLX context is returned with the connection and drawables it is
+    /// current on, none of which are closed or destroyed when the context goes away.
+    fn current() -> Option<GraphicsContext> {
+        let native = unsafe { glXGetCurrentContext() };
+        if native == ptr::mut_null() {
+            return None
+        }
+        match current_context.get().and_then(|current| current.upgrade(&native)) {
+            Some((resources, context)) => {
+                return Some(GraphicsContext {
+                    resources: resources,
+                    context: context,
+                    _no_sync: marker::NoSync,
+                })
+            }
+            None => {}
+        }
+
+        let (display, draw, read) = unsafe {
+            (glXGetCurrentDisplay(), glXGetCurrentDrawable(), glXGetCurrentReadDrawable())
+        };
+        let pixel_format = match GraphicsContext::context_fb_config(display, native) {
+            Ok(config) => GraphicsContext::granted_pixel_format(display, config),
+            Err(_) => Default::default(),
+        };
+        Some(GraphicsContext {
+            resources: Arc::new(NativeResources {
+                display: Arc::new(DisplayConnection {
+                    display: display,
+                    owned: false,
+                    _resource: TrackedResource::new(DisplayResource),
+                }),
+                surface: Mutex::new(ForeignSurface(draw, read)),
+                pixel_format: pixel_format,
+                owned_context: None,
+                _drawable_resource: TrackedResource::new(DrawableResource),
+                _context_resource: None,
+            }),
+            context: Arc::new(native),
+            _no_sync: marker::NoSync,
+        })
+    }
+
+    /// Returns the pixel format of the config the context was created with.
+    fn pixel_format(&self) -> PixelFormat {
+        self.resources.pixel_format.clone()
+    }
+
+    fn get_proc_address(&self, name: &str) -> Option<*const c_void> {
+        let address = name.with_c_str(|name| unsafe { glXGetProcAddress(name) });
+        if address == ptr::null() {
+            None
+        } else {
+            Some(address)
         }
     }
 }
@@ -264,10 +1371,279 @@ impl GraphicsContextMethods<GLXContext> for GraphicsContext {
 impl Clone for GraphicsContext {
     fn clone(&self) -> GraphicsContext {
         GraphicsContext {
-            display: self.display,
-            pixmap: self.pixmap,
+            resources: self.resources.clone(),
             context: self.context.clone(),
+            _no_sync: marker::NoSync,
+        }
+    }
+}
+
+
+/// A GLX context rendering directly into an X pixmap. Where the server supports DRI3, `id()`
+/// names the pixmap as a dma-buf, which any process can import. Otherwise it names the pixmap by
+/// its XID, which any other client of the same server can attach to.
+pub struct Context {
+    context: GraphicsContext,
+    // The pixmap exported through DRI3, with the descriptor that keeps it open.
+    dma_buf: Option<(DmaBuf, FileDescriptor)>,
+    generation: uint,
+    size: Size2D<int>,
+}
+
+impl Context {
+    // Exports the pixmap a share context renders into if the server supports DRI3.
+    fn export(display: *mut Display, surface: &Surface)
+              -> Result<Option<(DmaBuf, FileDescriptor)>,Error> {
+        let pixmap = match *surface {
+            PixmapSurface(pixmap, _) => pixmap,
+            PbufferSurface(_) | ForeignSurface(..) => {
+                fail!("share contexts always render into a pixmap")
+            }
+        };
+        match export_pixmap(display, pixmap) {
+            Ok(dma_buf) => Ok(Some(dma_buf)),
+            Err(ExtensionUnsupported(_)) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl ShareContext for Context {
+    fn try_new(size: Size2D<int>) -> Result<Context,Error> {
+        GraphicsContextBuilder::new().build_share_context(size)
+    }
+
+    /// Renders into a new pixmap of the given size, which has a new XID. If the pixmap cannot be
+    /// created or exported, the context keeps rendering into the old one.
+    fn try_resize(&mut self, size: Size2D<int>) -> Result<(),Error> {
+        let display = self.context.display();
+        let surface = try!(self.context.new_surface(RequirePixmap, size));
+        let dma_buf = match Context::export(display, &surface) {
+            Ok(dma_buf) => dma_buf,
+            Err(error) => {
+                surface.destroy(display);
+                return Err(error)
+            }
+        };
+        try!(self.context.replace_surface(surface));
+        self.generation += 1;
+        self.size = size;
+        self.dma_buf = dma_buf;
+        Ok(())
+    }
+
+    /// GLX has no fences that other processes can wait on, so this finishes rendering.
+    fn flush(&self) -> SyncToken {
+        self.context.make_current();
+        gl2::finish();
+
+        // Make sure the server has seen all of our rendering before the consumer reads it.
+        unsafe {
+            XSync(self.context.display(), 0);
+        }
+        FinishedSync
+    }
+
+    fn id(&self) -> SurfaceId {
+        match self.dma_buf {
+            Some((ref buffer, _)) => return DmaBufId(buffer.clone()),
+            None => {}
+        }
+        match *self.context.resources.surface.lock() {
+            PixmapSurface(pixmap, _) => X11DrawableId(pixmap as u64),
+            PbufferSurface(_) | ForeignSurface(..) => {
+                fail!("share contexts always render into a pixmap")
+            }
+        }
+    }
+
+    fn generation(&self) -> uint {
+        self.generation
+    }
+
+    fn size(&self) -> Size2D<int> {
+        self.size
+    }
+
+    fn format(&self) -> SurfaceFormat {
+        BGRA8Format
+    }
+
+    /// Returns the stride the server chose for the exported pixmap, which may include padding.
+    fn stride(&self) -> uint {
+        match self.dma_buf {
+            Some((ref buffer, _)) => buffer.planes[0].stride as uint,
+            None => self.size.width as uint * 4,
         }
     }
 }
 
+/// Reads a pixmap shared by a `Context` in another process, copying its contents into a texture
+/// owned by the receiving context.
+pub struct SurfaceReader {
+    context: GraphicsContext,
+    // The pixmap a shared dma-buf was imported into, which belongs to the reader.
+    imported_pixmap: Option<Pixmap>,
+    pixmap: GLXPixmap,
+    texture: GLuint,
+    size: Size2D<int>,
+    _pixmap_resource: TrackedResource,
+    _texture_resource: Option<TrackedResource>,
+}
+
+impl SurfaceReader {
+    // Makes the receiving context current on the shared pixmap, so that reads come from it.
+    fn try_bind_shared_pixmap(&self) -> Result<(),Error> {
+        unsafe {
+            let result = glXMakeContextCurrent(self.context.display(),
+                                               self.pixmap,
+                                               self.pixmap,
+                                               *self.context.context);
+            if result == 0 {
+                return Err(MakeCurrentFailed)
+            }
+            Ok(())
+        }
+    }
+
+    fn bind_shared_pixmap(&self) {
+        match self.try_bind_shared_pixmap() {
+            Ok(()) => {}
+            Err(error) => fail!("failed to bind shared pixmap: {}", error),
+        }
+    }
+}
+
+impl SharedSurfaceReader<GraphicsContext> for SurfaceReader {
+    fn try_open(context: &GraphicsContext, id: &SurfaceId, size: Size2D<int>)
+                -> Result<SurfaceReader,Error> {
+        let display = context.display();
+        let (id, imported_pixmap) = match *id {
+            X11DrawableId(id) => (id as Pixmap, None),
+            DmaBufId(ref buffer) => {
+                let pixmap = try!(import_dma_buf(display, buffer, size));
+                (pixmap, Some(pixmap))
+            }
+            _ => return Err(IncompatibleSurface),
+        };
+        unsafe {
+            // Attach with the receiving context's own config so that it can be made current on
+            // the pixmap.
+            let config = match GraphicsContext::context_fb_config(display, *context.context) {
+                Ok(config) => config,
+                Err(error) => {
+                    for &pixmap in imported_pixmap.iter() {
+                        XFreePixmap(display, pixmap);
+                    }
+                    return Err(error)
+                }
+            };
+
+            let pixmap = glXCreatePixmap(display, config, id, ptr::null());
+            debug!("attached to shared pixmap {}: glXCreatePixmap returned {}", id, pixmap);
+            if pixmap == 0 {
+                for &pixmap in imported_pixmap.iter() {
+                    XFreePixmap(display, pixmap);
+                }
+                return Err(SurfaceCreationFailed)
+            }
+
+            let mut reader = SurfaceReader {
+                context: context.clone(),
+                imported_pixmap: imported_pixmap,
+                pixmap: pixmap,
+                texture: 0,
+                size: size,
+                _pixmap_resource: TrackedResource::new(DrawableResource),
+                _texture_resource: None,
+            };
+
+            try!(reader.try_bind_shared_pixmap());
+            let texture = gl2::gen_textures(1)[0];
+            reader.texture = texture;
+            reader._texture_resource = Some(TrackedResource::new(TextureResource));
+            gl2::bind_texture(TEXTURE_2D, texture);
+            gl2::tex_parameter_i(TEXTURE_2D, TEXTURE_MAG_FILTER, LINEAR as GLint);
+            gl2::tex_parameter_i(TEXTURE_2D, TEXTURE_MIN_FILTER, NEAREST as GLint);
+            gl2::copy_tex_image_2d(TEXTURE_2D,
+                                   0,
+                                   RGBA,
+                                   0,
+                                   0,
+                                   size.width as GLsizei,
+                                   size.height as GLsizei,
+                                   0);
+            try!(context.try_make_current());
+
+            Ok(reader)
+        }
+    }
+
+    fn texture(&self) -> GLuint {
+        self.texture
+    }
+
+    fn update(&self) {
+        self.bind_shared_pixmap();
+        gl2::bind_texture(TEXTURE_2D, self.texture);
+        gl2::copy_tex_sub_image_2d(TEXTURE_2D,
+                                   0,
+                                   0,
+                                   0,
+                                   0,
+                                   0,
+                                   self.size.width as GLsizei,
+                                   self.size.height as GLsizei);
+        self.context.make_current();
+    }
+
+    fn read_pixels(&self) -> Vec<u8> {
+        self.bind_shared_pixmap();
+        let pixels = gl2::read_pixels(0,
+                                      0,
+                                      self.size.width as GLsizei,
+                                      self.size.height as GLsizei,
+                                      RGBA,
+                                      UNSIGNED_BYTE);
+        self.context.make_current();
+        pixels
+    }
+}
+
+impl Drop for SurfaceReader {
+    fn drop(&mut self) {
+        // Delete the texture in the context that owns it, then detach from the shared pixmap. The
+        // pixmap itself belongs to the producer, unless it was imported from a dma-buf.
+        if self.context.try_make_current().is_ok() && self.texture != 0 {
+            gl2::delete_textures([self.texture]);
+        }
+        unsafe {
+            glXDestroyPixmap(self.context.display(), self.pixmap);
+            match self.imported_pixmap {
+                Some(pixmap) => {
+                    XFreePixmap(self.context.display(), pixmap);
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+#[test]
+fn share_contexts_are_named_by_their_pixmap_without_dri3() {
+    // Runs against whatever server `$DISPLAY` names, such as Xvfb on CI, and does nothing without
+    // one. Servers with DRI3 export a dma-buf instead.
+    let share_context: Context = match ShareContext::try_new(Size2D(4, 2)) {
+        Ok(share_context) => share_context,
+        Err(NoDisplay) => return,
+        Err(error) => fail!("failed to create share context: {}", error),
+    };
+    let has_dri3 = dri3_connection(share_context.context.display()).is_ok();
+    match share_context.id() {
+        DmaBufId(_) if has_dri3 => {}
+        X11DrawableId(_) if !has_dri3 => {}
+        id => fail!("unexpected ID {} when the server {} DRI3",
+                    id,
+                    if has_dri3 { "supports" } else { "lacks" }),
+    }
+}
@@ -7,8 +7,15 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use base::ShareContext;
-use context::GraphicsContextMethods;
+use attributes::{CompatibilityProfile, ContextAttributes, OpenGLES, PixelFormat};
+use base::{BGRA8Format, IOSurfaceId, ShareContext, SharedSurfaceReader, SurfaceFormat};
+use base::SurfaceId;
+use context::{CurrentContext, GraphicsContextMethods};
+use error::{ContextCreationFailed, Error, FramebufferIncomplete, MakeCurrentFailed};
+use error::{ExtensionUnsupported, IncompatibleSurface, NoMatchingVisual, SurfaceCreationFailed};
+use fence::{FlushedSync, SyncToken};
+use resources::{ContextResource, DrawableResource, FramebufferResource, TextureResource};
+use resources::TrackedResource;
 
 use sync::Arc;
 use geom::size::Size2D;
@@ -16,34 +23,138 @@ use io_surface::{IOSurface, kIOSurfaceBytesPerElement, kIOSurfaceBytesPerRow};
 use io_surface::{kIOSurfaceHeight, kIOSurfaceIsGlobal, kIOSurfaceWidth};
 use opengles::cgl::{CGLChoosePixelFormat, CGLContextObj, CGLCreateContext};
 use opengles::cgl::{CGLSetCurrentContext, CGLTexImageIOSurface2D, kCGLNoError, kCGLPFACompliant};
+use opengles::cgl::{CGLGetCurrentContext, CGLPixelFormatAttribute, CGLPixelFormatObj};
 use opengles::cgl::{kCGLPFADoubleBuffer};
 use opengles::gl2::{BGRA, CLAMP_TO_EDGE, COLOR_ATTACHMENT0, FRAMEBUFFER};
 use opengles::gl2::{FRAMEBUFFER_COMPLETE, GLenum, GLint, GLsizei, GLuint, LINEAR};
-use opengles::gl2::{NEAREST, RGBA, TEXTURE_MAG_FILTER, TEXTURE_MIN_FILTER};
+use opengles::gl2::{NEAREST, RGBA, TEXTURE_MAG_FILTER, TEXTURE_MIN_FILTER, UNSIGNED_BYTE};
 use opengles::gl2::{TEXTURE_RECTANGLE_ARB, TEXTURE_WRAP_S, TEXTURE_WRAP_T};
 use opengles::gl2::{UNSIGNED_INT_8_8_8_8_REV};
 use opengles::gl2;
 
+use libc::{c_int, c_void};
+use std::default::Default;
+use std::dynamic_lib::DynamicLibrary;
+use std::kinds::marker;
 use std::mem;
 use std::ptr;
 
+// Pixel format attributes not exported by `opengles::cgl`.
+
+static kCGLPFAColorSize: CGLPixelFormatAttribute = 8;
+static kCGLPFAAlphaSize: CGLPixelFormatAttribute = 11;
+static kCGLPFADepthSize: CGLPixelFormatAttribute = 12;
+static kCGLPFAStencilSize: CGLPixelFormatAttribute = 13;
+static kCGLPFASampleBuffers: CGLPixelFormatAttribute = 55;
+static kCGLPFASamples: CGLPixelFormatAttribute = 56;
+static kCGLPFAMultisample: CGLPixelFormatAttribute = 59;
+static kCGLPFAOpenGLProfile: CGLPixelFormatAttribute = 99;
+
+// Values for `kCGLPFAOpenGLProfile`.
+
+static kCGLOGLPVersion_Legacy: CGLPixelFormatAttribute = 0x1000;
+static kCGLOGLPVersion_3_2_Core: CGLPixelFormatAttribute = 0x3200;
+static kCGLOGLPVersion_GL4_Core: CGLPixelFormatAttribute = 0x4100;
+
+static OPENGL_FRAMEWORK_PATH: &'static str =
+    "/System/Library/Frameworks/OpenGL.framework/OpenGL";
+
+#[link(name = "OpenGL", kind = "framework")]
+extern {
+    fn CGLReleaseContext(ctx: CGLContextObj);
+    fn CGLReleasePixelFormat(pix: CGLPixelFormatObj);
+    fn CGLGetPixelFormat(ctx: CGLContextObj) -> CGLPixelFormatObj;
+    fn CGLDescribePixelFormat(pix: CGLPixelFormatObj,
+                              pix_num: c_int,
+                              attrib: CGLPixelFormatAttribute,
+                              value: *mut c_int)
+                              -> c_int;
+}
+
+// Returns the attributes contexts are created with by default. CGL contexts have always been
+// double-buffered and created without a depth buffer.
+fn default_attributes() -> ContextAttributes {
+    let mut attributes: ContextAttributes = Default::default();
+    attributes.pixel_format.depth_bits = 0;
+    attributes.pixel_format.double_buffered = true;
+    attributes
+}
+
+/// The CGL context backing a `GraphicsContext`, released when the last clone goes away.
+struct NativeContext {
+    // The CGL context, if we created it. Wrapped contexts are owned by whoever created them.
+    owned_context: Option<CGLContextObj>,
+    _resource: Option<TrackedResource>,
+}
+
+impl Drop for NativeContext {
+    fn drop(&mut self) {
+        match self.owned_context {
+            Some(cgl_context) => {
+                unsafe {
+                    if CGLGetCurrentContext() == cgl_context {
+                        CGLSetCurrentContext(ptr::mut_null());
+                    }
+                    CGLReleaseContext(cgl_context);
+                }
+            }
+            None => {}
+        }
+    }
+}
+
 /// Mac-specific interface to 3D graphics contexts.
+///
+/// Clones share the same CGL context, which is released when the last clone is dropped.
 pub struct GraphicsContext {
+    native_context: Arc<NativeContext>,
     cgl_context: Arc<CGLContextObj>,
+    // A context can be sent to another thread but not used from two threads at once.
+    _no_sync: marker::NoSync,
 }
 
 impl GraphicsContext {
     /// Returns a new context, possibly shared with another context.
-    fn new_possibly_shared(share_context: Option<GraphicsContext>) -> GraphicsContext {
+    fn new_possibly_shared(share_context: Option<&GraphicsContext>,
+                           attributes: &ContextAttributes)
+                           -> Result<GraphicsContext,Error> {
+        let profile = try!(GraphicsContext::choose_profile(attributes));
+
         unsafe {
             // Choose a pixel format.
-            let attributes = [ kCGLPFADoubleBuffer, kCGLPFACompliant, 0 ];
+            let requested = &attributes.pixel_format;
+            let mut pixel_format_attributes = vec!(
+                kCGLPFACompliant,
+                kCGLPFAOpenGLProfile, profile,
+                kCGLPFAColorSize,
+                (requested.red_bits + requested.green_bits + requested.blue_bits) as
+                    CGLPixelFormatAttribute,
+                kCGLPFAAlphaSize, requested.alpha_bits as CGLPixelFormatAttribute,
+                kCGLPFADepthSize, requested.depth_bits as CGLPixelFormatAttribute,
+                kCGLPFAStencilSize, requested.stencil_bits as CGLPixelFormatAttribute,
+            );
+            if requested.samples > 0 {
+                pixel_format_attributes.push_all([
+                    kCGLPFAMultisample,
+                    kCGLPFASampleBuffers, 1,
+                    kCGLPFASamples, requested.samples as CGLPixelFormatAttribute,
+                ]);
+            }
+            if requested.double_buffered {
+                pixel_format_attributes.push(kCGLPFADoubleBuffer);
+            }
+            // sRGB is a property of framebuffer writes in CGL, not of the pixel format.
+            pixel_format_attributes.push(0);
+
             let mut pixel_format = ptr::mut_null();
             let mut pixel_format_count = 1;
-            let gl_error = CGLChoosePixelFormat(mem::transmute(&attributes[0]),
+            let gl_error = CGLChoosePixelFormat(mem::transmute(pixel_format_attributes.as_ptr()),
                                                 &mut pixel_format,
                                                 &mut pixel_format_count);
-            assert!(gl_error == kCGLNoError);
+            if gl_error != kCGLNoError || pixel_format == ptr::mut_null() {
+                debug!("CGLChoosePixelFormat returned {}", gl_error);
+                return Err(NoMatchingVisual)
+            }
 
             // Create the context.
             let mut cgl_context = ptr::mut_null();
@@ -54,63 +165,226 @@ impl GraphicsContext {
                     CGLCreateContext(pixel_format, *native, &mut cgl_context)
                 }
             };
-            assert!(gl_error == kCGLNoError);
+            CGLReleasePixelFormat(pixel_format);
+            if gl_error != kCGLNoError {
+                debug!("CGLCreateContext returned {}", gl_error);
+                return Err(ContextCreationFailed)
+            }
+
+            Ok(GraphicsContext {
+                native_context: Arc::new(NativeContext {
+                    owned_context: Some(cgl_context),
+                    _resource: Some(TrackedResource::new(ContextResource)),
+                }),
+                cgl_context: Arc::new(cgl_context),
+                _no_sync: marker::NoSync,
+            })
+        }
+    }
+}
 
-            GraphicsContextMethods::wrap(Arc::new(cgl_context))
+impl GraphicsContext {
+    // Returns the CGL profile that provides the requested version. CGL only offers OpenGL 2.1 in
+    // the legacy profile and later versions in the core profile, and has no OpenGL ES or debug
+    // contexts. Every CGL context is forward-compatible.
+    fn choose_profile(attributes: &ContextAttributes) -> Result<CGLPixelFormatAttribute,Error> {
+        if attributes.api == OpenGLES {
+            return Err(ExtensionUnsupported("OpenGL ES"))
+        }
+        if attributes.debug {
+            return Err(ExtensionUnsupported("debug contexts"))
+        }
+        match attributes.version {
+            None => Ok(kCGLOGLPVersion_Legacy),
+            Some(version) if version <= (2, 1) => Ok(kCGLOGLPVersion_Legacy),
+            Some(_) if attributes.profile == CompatibilityProfile &&
+                    !attributes.forward_compatible => {
+                Err(ExtensionUnsupported("OpenGL 3 compatibility profile"))
+            }
+            Some(version) if version <= (3, 2) => Ok(kCGLOGLPVersion_3_2_Core),
+            Some(_) => Ok(kCGLOGLPVersion_GL4_Core),
         }
     }
 }
 
+local_data_key!(current_context: CurrentContext<NativeContext, CGLContextObj>)
+
 impl GraphicsContextMethods<CGLContextObj> for GraphicsContext {
-    /// Wraps the given instance of the native Core OpenGL graphics context.
+    /// Wraps the given instance of the native Core OpenGL graphics context. The wrapped context is
+    /// not released when this `GraphicsContext` goes away.
     fn wrap(instance: Arc<CGLContextObj>) -> GraphicsContext {
         GraphicsContext {
-            cgl_context: instance
+            native_context: Arc::new(NativeContext {
+                owned_context: None,
+                _resource: None,
+            }),
+            cgl_context: instance,
+            _no_sync: marker::NoSync,
         }
     }
 
-    /// Returns the underlying native 3D context without modifying its reference count.
+    /// Returns the underlying native 3D context. The native context is only valid for as long as
+    /// this context or one of its clones is alive.
     fn native(&self) -> Arc<CGLContextObj> {
         self.cgl_context.clone()
     }
 
-    /// Creates a new offscreen 3D graphics context.
-    fn new() -> GraphicsContext {
-        GraphicsContext::new_possibly_shared(None)
+    /// Creates a new double-buffered offscreen 3D graphics context without a depth buffer.
+    fn try_new() -> Result<GraphicsContext,Error> {
+        GraphicsContext::new_possibly_shared(None, &default_attributes())
     }
 
-    /// Creates a new offscreen 3D graphics context shared with the given context.
-    fn new_shared(share_context: GraphicsContext) -> GraphicsContext {
-        GraphicsContext::new_possibly_shared(Some(share_context))
+    /// Creates a new double-buffered offscreen 3D graphics context without a depth buffer, shared
+    /// with this context.
+    fn try_new_shared(&self) -> Result<GraphicsContext,Error> {
+        GraphicsContext::new_possibly_shared(Some(self), &default_attributes())
+    }
+
+    /// Creates a new offscreen 3D graphics context with the given attributes.
+    fn try_new_with_attributes(attributes: &ContextAttributes) -> Result<GraphicsContext,Error> {
+        GraphicsContext::new_possibly_shared(None, attributes)
+    }
+
+    /// Creates a new offscreen 3D graphics context with the given attributes, shared with this
+    /// context.
+    fn try_new_shared_with_attributes(&self, attributes: &ContextAttributes)
+                                      -> Result<GraphicsContext,Error> {
+        GraphicsContext::new_possibly_shared(Some(self), attributes)
     }
 
     /// Makes this context the current context.
-    fn make_current(&self) {
+    fn try_make_current(&self) -> Result<(),Error> {
         unsafe {
             let gl_error = CGLSetCurrentContext(*self.cgl_context);
-            assert!(gl_error == kCGLNoError)
+            if gl_error != kCGLNoError {
+                debug!("CGLSetCurrentContext returned {}", gl_error);
+                return Err(MakeCurrentFailed)
+            }
+            current_context.replace(Some(CurrentContext::new(&self.native_context,
+                                                             &self.cgl_context)));
+            Ok(())
+        }
+    }
+
+    fn try_release_current(&self) -> Result<(),Error> {
+        if !self.is_current() {
+            return Ok(())
+        }
+        unsafe {
+            let result = CGLSetCurrentContext(ptr::mut_null());
+            if result != kCGLNoError {
+                debug!("CGLSetCurrentContext returned {}", result);
+                return Err(MakeCurrentFailed)
+            }
+            current_context.replace(None);
+            Ok(())
+        }
+    }
+
+    fn is_current(&self) -> bool {
+        unsafe {
+            CGLGetCurrentContext() == *self.cgl_context
+        }
+    }
+
+    /// Returns the current context. Contexts this task made current are returned along with their
+    /// resources. CGL contexts have no drawable, so any other context is simply wrapped.
+    fn current() -> Option<GraphicsContext> {
+        let native = unsafe { CGLGetCurrentContext() };
+        if native == ptr::mut_null() {
+            return None
+        }
+        match current_context.get().and_then(|current| current.upgrade(&native)) {
+            Some((native_context, cgl_context)) => {
+                Some(GraphicsContext {
+                    native_context: native_context,
+                    cgl_context: cgl_context,
+                    _no_sync: marker::NoSync,
+                })
+            }
+            None => Some(GraphicsContextMethods::wrap(Arc::new(native))),
+        }
+    }
+
+    /// Returns the pixel format the CGL context was created with.
+    fn pixel_format(&self) -> PixelFormat {
+        unsafe {
+            let pixel_format = CGLGetPixelFormat(*self.cgl_context);
+            let get = |attribute: CGLPixelFormatAttribute| -> u8 {
+                let mut value = 0;
+                if CGLDescribePixelFormat(pixel_format, 0, attribute, &mut value) != kCGLNoError {
+                    return 0
+                }
+                value as u8
+            };
+
+            // CGL only reports the combined size of the color channels, including alpha.
+            let alpha_bits = get(kCGLPFAAlphaSize);
+            let channel_bits = get(kCGLPFAColorSize).saturating_sub(alpha_bits) / 3;
+            PixelFormat {
+                red_bits: channel_bits,
+                green_bits: channel_bits,
+                blue_bits: channel_bits,
+                alpha_bits: alpha_bits,
+                depth_bits: get(kCGLPFADepthSize),
+                stencil_bits: get(kCGLPFAStencilSize),
+                samples: if get(kCGLPFASampleBuffers) != 0 { get(kCGLPFASamples) } else { 0 },
+                srgb: false,
+                double_buffered: get(kCGLPFADoubleBuffer) != 0,
+            }
+        }
+    }
+
+    /// Looks the function up in the OpenGL framework, which every CGL context shares.
+    fn get_proc_address(&self, name: &str) -> Option<*const c_void> {
+        let framework = match DynamicLibrary::open(Some(&Path::new(OPENGL_FRAMEWORK_PATH))) {
+            Ok(framework) => framework,
+            Err(_) => return None,
+        };
+        unsafe {
+            framework.symbol::<c_void>(name).ok().map(|address| address as *const c_void)
         }
     }
 }
 
 impl Clone for GraphicsContext {
     fn clone(&self) -> GraphicsContext {
-        GraphicsContextMethods::wrap(self.native())
+        GraphicsContext {
+            native_context: self.native_context.clone(),
+            cgl_context: self.cgl_context.clone(),
+            _no_sync: marker::NoSync,
+        }
     }
 }
 
+/// A CGL context rendering into an IOSurface through a framebuffer. The framebuffer and texture
+/// are deleted, and the CGL context released, when the `Context` is dropped.
 pub struct Context {
+    context: GraphicsContext,
     surface: IOSurface,
-    _framebuffer: GLuint,
-    _texture: GLuint
-    
-    // FIXME: Needs drop.
+    framebuffer: GLuint,
+    texture: GLuint,
+    generation: uint,
+    size: Size2D<int>,
+    _surface_resource: TrackedResource,
+    _framebuffer_resource: TrackedResource,
+    _texture_resource: TrackedResource,
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        if self.context.try_make_current().is_ok() {
+            gl2::bind_framebuffer(FRAMEBUFFER, 0);
+            gl2::delete_frame_buffers([self.framebuffer]);
+            gl2::delete_textures([self.texture]);
+        }
+    }
 }
 
-pub fn init_cgl() -> GraphicsContext {
-    let context: GraphicsContext = GraphicsContextMethods::new();
-    context.make_current();
-    context
+pub fn init_cgl() -> Result<GraphicsContext,Error> {
+    let context: GraphicsContext = try!(GraphicsContextMethods::try_new());
+    try!(context.try_make_current());
+    Ok(context)
 }
 
 
@@ -131,10 +405,12 @@ pub fn init_surface(size: Size2D<int>) -> IOSurface {
     let v_height: CFNumber = FromPrimitive::from_i32(size.height as i32).unwrap();
 
     let k_bytes_per_row: CFString = unsafe { TCFType::wrap_under_get_rule(kIOSurfaceBytesPerRow) };
-    let v_bytes_per_row: CFNumber = FromPrimitive::from_i32(size.width as i32 * 4).unwrap();
+    let bytes_per_pixel = BGRA8Format.bytes_per_pixel() as i32;
+    let v_bytes_per_row: CFNumber = FromPrimitive::from_i32(size.width as i32 * bytes_per_pixel)
+        .unwrap();
 
     let k_bytes_per_elem: CFString = unsafe { TCFType::wrap_under_get_rule(kIOSurfaceBytesPerElement) };
-    let v_bytes_per_elem: CFNumber = FromPrimitive::from_i32(4i32).unwrap();
+    let v_bytes_per_elem: CFNumber = FromPrimitive::from_i32(bytes_per_pixel).unwrap();
 
     let k_is_global: CFString = unsafe { TCFType::wrap_under_get_rule(kIOSurfaceIsGlobal) };
     let v_is_global = CFBoolean::true_value();
@@ -163,7 +439,8 @@ pub fn init_texture() -> GLuint {
 }
 
 // Assumes the texture is already bound via gl2::bind_texture().
-pub fn bind_surface_to_texture(context: &GraphicsContext, surface: &IOSurface, size: Size2D<int>) {
+pub fn bind_surface_to_texture(context: &GraphicsContext, surface: &IOSurface, size: Size2D<int>)
+                               -> Result<(),Error> {
     use core_foundation::base::TCFType;
     // FIXME: There should be safe wrappers for this.
     unsafe {
@@ -177,20 +454,28 @@ pub fn bind_surface_to_texture(context: &GraphicsContext, surface: &IOSurface, s
                                               UNSIGNED_INT_8_8_8_8_REV,
                                               mem::transmute(surface.as_concrete_TypeRef()),
                                               0);
-        assert!(gl_error == kCGLNoError);
+        if gl_error != kCGLNoError {
+            debug!("CGLTexImageIOSurface2D returned {}", gl_error);
+            return Err(SurfaceCreationFailed)
+        }
+        Ok(())
     }
 }
 
-pub fn bind_texture_to_framebuffer(texture: GLuint) {
+pub fn bind_texture_to_framebuffer(texture: GLuint) -> Result<(),Error> {
     gl2::bind_texture(TEXTURE_RECTANGLE_ARB, 0);
     gl2::framebuffer_texture_2d(FRAMEBUFFER, COLOR_ATTACHMENT0, TEXTURE_RECTANGLE_ARB, texture, 0);
-    assert!(gl2::check_framebuffer_status(FRAMEBUFFER) == FRAMEBUFFER_COMPLETE);
+    let status = gl2::check_framebuffer_status(FRAMEBUFFER);
+    if status != FRAMEBUFFER_COMPLETE {
+        return Err(FramebufferIncomplete(status))
+    }
+    Ok(())
 }
 
 impl ShareContext for Context {
-    fn new(size: Size2D<int>) -> Context {
+    fn try_new(size: Size2D<int>) -> Result<Context,Error> {
         // Initialize CGL.
-        let context = init_cgl();
+        let context = try!(init_cgl());
 
         // Create the surface.
         let surface = init_surface(size.clone());
@@ -199,26 +484,154 @@ impl ShareContext for Context {
         let framebuffer = gl2::gen_framebuffers(1)[0];
         gl2::bind_framebuffer(FRAMEBUFFER, framebuffer);
 
-        // Create and bind to the texture.
+        // Create the texture. From here on, dropping the context cleans up after us.
         let texture = init_texture();
-        bind_surface_to_texture(&context, &surface, size);
-
-        // Bind the texture to the framebuffer.
-        bind_texture_to_framebuffer(texture);
-
-        Context {
+        let share_context = Context {
+            context: context,
             surface: surface,
-            _framebuffer: framebuffer,
-            _texture: texture
+            framebuffer: framebuffer,
+            texture: texture,
+            generation: 0,
+            size: size,
+            _surface_resource: TrackedResource::new(DrawableResource),
+            _framebuffer_resource: TrackedResource::new(FramebufferResource),
+            _texture_resource: TrackedResource::new(TextureResource),
+        };
+
+        // Bind the surface to the texture, and the texture to the framebuffer.
+        try!(bind_surface_to_texture(&share_context.context, &share_context.surface, size));
+        try!(bind_texture_to_framebuffer(texture));
+
+        Ok(share_context)
+    }
+
+    /// Binds a new IOSurface of the given size to the texture. The new surface has a new ID; the
+    /// old one is released once consumers let go of it. If the new surface cannot be bound, the
+    /// context keeps rendering into the old one.
+    fn try_resize(&mut self, size: Size2D<int>) -> Result<(),Error> {
+        try!(self.context.try_make_current());
+        let surface = init_surface(size);
+
+        gl2::bind_framebuffer(FRAMEBUFFER, self.framebuffer);
+        gl2::bind_texture(TEXTURE_RECTANGLE_ARB, self.texture);
+        let result = bind_surface_to_texture(&self.context, &surface, size).and_then(|()| {
+            bind_texture_to_framebuffer(self.texture)
+        });
+        match result {
+            Ok(()) => {}
+            Err(error) => {
+                // Put the old surface back so that rendering carries on into it.
+                if bind_surface_to_texture(&self.context, &self.surface, self.size).is_err() {
+                    debug!("could not rebind the old surface after a failed resize");
+                }
+                return Err(error)
+            }
         }
+
+        self.surface = surface;
+        self.generation += 1;
+        self.size = size;
+        Ok(())
+    }
+
+    /// Submits the rendering without waiting for it. The system orders GPU access to an
+    /// IOSurface, so a consumer that binds the surface afterwards sees the finished frame.
+    fn flush(&self) -> SyncToken {
+        self.context.make_current();
+        gl2::flush();
+        FlushedSync
+    }
+
+    fn id(&self) -> SurfaceId {
+        IOSurfaceId(self.surface.get_id() as u32)
+    }
+
+    fn generation(&self) -> uint {
+        self.generation
     }
 
-    fn flush(&self) {
-        gl2::finish();
+    fn size(&self) -> Size2D<int> {
+        self.size
     }
 
-    fn id(&self) -> int {
-        self.surface.get_id() as int
+    fn format(&self) -> SurfaceFormat {
+        BGRA8Format
+    }
+
+    fn framebuffer(&self) -> GLuint {
+        self.framebuffer
+    }
+}
+
+
+/// Reads an IOSurface shared by a `Context` in another process. The surface is bound directly to
+/// a rectangle texture in the receiving context, so no copies are made.
+pub struct SurfaceReader {
+    context: GraphicsContext,
+    _surface: IOSurface,
+    texture: GLuint,
+    size: Size2D<int>,
+    _texture_resource: TrackedResource,
+}
+
+impl SharedSurfaceReader<GraphicsContext> for SurfaceReader {
+    fn try_open(context: &GraphicsContext, id: &SurfaceId, size: Size2D<int>)
+                -> Result<SurfaceReader,Error> {
+        use io_surface;
+
+        let id = match *id {
+            IOSurfaceId(id) => id,
+            _ => return Err(IncompatibleSurface),
+        };
+        try!(context.try_make_current());
+        let surface = io_surface::lookup(id as io_surface::IOSurfaceID);
+        let texture = init_texture();
+        let reader = SurfaceReader {
+            context: context.clone(),
+            _surface: surface,
+            texture: texture,
+            size: size,
+            _texture_resource: TrackedResource::new(TextureResource),
+        };
+        try!(bind_surface_to_texture(context, &reader._surface, size));
+        gl2::bind_texture(TEXTURE_RECTANGLE_ARB, 0);
+
+        Ok(reader)
+    }
+
+    fn texture(&self) -> GLuint {
+        self.texture
+    }
+
+    fn update(&self) {
+        // The texture is backed by the IOSurface itself, so it is always up to date.
+    }
+
+    fn read_pixels(&self) -> Vec<u8> {
+        self.context.make_current();
+
+        let framebuffer = gl2::gen_framebuffers(1)[0];
+        gl2::bind_framebuffer(FRAMEBUFFER, framebuffer);
+        match bind_texture_to_framebuffer(self.texture) {
+            Ok(()) => {}
+            Err(error) => fail!("failed to read shared surface: {}", error),
+        }
+        let pixels = gl2::read_pixels(0,
+                                      0,
+                                      self.size.width as GLsizei,
+                                      self.size.height as GLsizei,
+                                      RGBA,
+                                      UNSIGNED_BYTE);
+        gl2::bind_framebuffer(FRAMEBUFFER, 0);
+        gl2::delete_frame_buffers([framebuffer]);
+        pixels
     }
 }
 
+impl Drop for SurfaceReader {
+    fn drop(&mut self) {
+        if self.context.try_make_current().is_ok() {
+            gl2::delete_textures([self.texture]);
+        }
+    }
+}
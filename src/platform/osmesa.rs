@@ -0,0 +1,691 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A pure-software backend built on OSMesa, which renders into plain CPU memory.
+//!
+//! Rendering is deterministic and needs neither an X server nor a GPU, which makes this backend
+//! suitable for golden-image tests. Share contexts render directly into a POSIX shared-memory
+//! region, whose ID is returned by `id()`.
+
+use attributes::{ContextAttributes, OpenGLES, PixelFormat};
+use base::{RGBA8Format, ShareContext, ShmId, ShmRegionId, SurfaceFormat, SurfaceId};
+use context::{CurrentContext, GraphicsContextMethods};
+use error::{ContextCreationFailed, Error, ExtensionUnsupported, MakeCurrentFailed};
+use fence::SyncToken;
+use readback::ShmSurfaceReader;
+use resources::{ContextResource, DrawableResource, TrackedResource};
+use shm::{FrameCounter, SharedMemory};
+
+use geom::size::Size2D;
+use libc::{c_char, c_int, c_uchar, c_void};
+use opengles::gl2::{GLenum, GLint, GLsizei, UNSIGNED_BYTE};
+use opengles::gl2;
+use std::c_str::ToCStr;
+use std::default::Default;
+use std::kinds::marker;
+use std::mem;
+use std::ptr;
+use std::slice;
+use sync::{Arc, Mutex};
+
+// Types.
+
+struct OSMesaContextOpaque;
+pub type OSMesaContext = *mut OSMesaContextOpaque;
+
+type OSMesaCreateContextAttribsFn = extern "C" fn(attribList: *const c_int,
+                                                  sharelist: OSMesaContext)
+                                                  -> OSMesaContext;
+
+// Constants.
+
+static OSMESA_RGBA: GLenum = 0x1908;
+
+static OSMESA_FORMAT: c_int = 0x22;
+static OSMESA_DEPTH_BITS: c_int = 0x30;
+static OSMESA_STENCIL_BITS: c_int = 0x31;
+static OSMESA_ACCUM_BITS: c_int = 0x32;
+static OSMESA_PROFILE: c_int = 0x33;
+static OSMESA_CORE_PROFILE: c_int = 0x34;
+static OSMESA_COMPAT_PROFILE: c_int = 0x35;
+static OSMESA_CONTEXT_MAJOR_VERSION: c_int = 0x36;
+static OSMESA_CONTEXT_MINOR_VERSION: c_int = 0x37;
+
+// External bindings to OSMesa.
+
+#[link(name = "OSMesa")]
+extern {
+    fn OSMesaCreateContextExt(format: GLenum,
+                              depthBits: GLint,
+                              stencilBits: GLint,
+                              accumBits: GLint,
+                              sharelist: OSMesaContext)
+                              -> OSMesaContext;
+    fn OSMesaDestroyContext(ctx: OSMesaContext);
+    fn OSMesaMakeCurrent(ctx: OSMesaContext,
+                         buffer: *mut c_void,
+                         type_: GLenum,
+                         width: GLsizei,
+                         height: GLsizei)
+                         -> c_uchar;
+    fn OSMesaGetCurrentContext() -> OSMesaContext;
+    fn OSMesaGetColorBuffer(c: OSMesaContext,
+                            width: *mut GLint,
+                            height: *mut GLint,
+                            format: *mut GLint,
+                            buffer: *mut *mut c_void)
+                            -> c_uchar;
+    fn OSMesaGetProcAddress(funcName: *const c_char) -> *const c_void;
+}
+
+// Implementation
+
+// Returns the attributes contexts are created with by default. OSMesa contexts have always been
+// created with an 8-bit stencil buffer, and always render into 8-bit RGBA.
+fn default_attributes() -> ContextAttributes {
+    let mut attributes: ContextAttributes = Default::default();
+    attributes.pixel_format.alpha_bits = 8;
+    attributes.pixel_format.stencil_bits = 8;
+    attributes
+}
+
+/// The memory an OSMesa context renders into: either private to the context, shared with other
+/// processes, or owned by whoever made a foreign context current.
+enum ColorBuffer {
+    PrivateBuffer(Vec<u8>),
+    SharedBuffer(SharedMemory),
+    ForeignBuffer(*mut c_void),
+}
+
+impl ColorBuffer {
+    // Returns the pointer OSMesa renders through. The backing lock is held mutably while it is
+    // taken, since OSMesa writes to private buffers as well.
+    fn as_mut_ptr(&mut self) -> *mut c_void {
+        match *self {
+            PrivateBuffer(ref mut buffer) => buffer.as_mut_ptr() as *mut c_void,
+            SharedBuffer(ref memory) => memory.as_mut_ptr() as *mut c_void,
+            ForeignBuffer(buffer) => buffer,
+        }
+    }
+}
+
+// The buffer a context renders into and its size. Resizing a share context replaces both.
+struct Backing {
+    buffer: ColorBuffer,
+    size: Size2D<int>,
+}
+
+/// The OSMesa context and the buffer it renders into. These are destroyed when the last clone of
+/// the context goes away.
+struct NativeResources {
+    backing: Mutex<Backing>,
+    pixel_format: PixelFormat,
+    // The OSMesa context, if we created it. Wrapped contexts are owned by whoever created them.
+    owned_context: Option<OSMesaContext>,
+    _buffer_resource: TrackedResource,
+    _context_resource: Option<TrackedResource>,
+}
+
+impl Drop for NativeResources {
+    fn drop(&mut self) {
+        match self.owned_context {
+            Some(context) => {
+                unsafe {
+                    if OSMesaGetCurrentContext() == context {
+                        OSMesaMakeCurrent(ptr::mut_null(), ptr::mut_null(), 0, 0, 0);
+                    }
+                    OSMesaDestroyContext(context);
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+/// OSMesa interface to 3D graphics contexts.
+///
+/// Clones share the same OSMesa context and color buffer; these are released when the last clone
+/// is dropped.
+pub struct GraphicsContext {
+    resources: Arc<NativeResources>,
+    context: Arc<OSMesaContext>,
+    // A context can be sent to another thread but not used from two threads at once.
+    _no_sync: marker::NoSync,
+}
+
+impl GraphicsContext {
+    // Creates a new, possibly shared, OSMesa context rendering into the given buffer.
+    fn new_possibly_shared(share_context: Option<&GraphicsContext>,
+                           attributes: &ContextAttributes,
+                           buffer: ColorBuffer,
+                           size: Size2D<int>)
+                           -> Result<GraphicsContext,Error> {
+        let native_share_context = match share_context {
+            None => ptr::mut_null(),
+            Some(share_context) => *share_context.native(),
+        };
+        let pixel_format = GraphicsContext::granted_pixel_format(&attributes.pixel_format);
+
+        unsafe {
+            let context = try!(GraphicsContext::create_context(attributes,
+                                                               &pixel_format,
+                                                               native_share_context));
+            Ok(GraphicsContext {
+                resources: Arc::new(NativeResources {
+                    backing: Mutex::new(Backing {
+                        buffer: buffer,
+                        size: size,
+                    }),
+                    pixel_format: pixel_format,
+                    owned_context: Some(context),
+                    _buffer_resource: TrackedResource::new(DrawableResource),
+                    _context_resource: Some(TrackedResource::new(ContextResource)),
+                }),
+                context: Arc::new(context),
+                _no_sync: marker::NoSync,
+            })
+        }
+    }
+
+    // Creates an OSMesa context. OSMesa only implements desktop OpenGL, and a specific version or
+    // profile needs `OSMesaCreateContextAttribs`, which Mesa added in 12.0. OSMesa has no context
+    // flags, so debug and forward-compatible requests are ignored.
+    unsafe fn create_context(attributes: &ContextAttributes,
+                             pixel_format: &PixelFormat,
+                             share_context: OSMesaContext)
+                             -> Result<OSMesaContext,Error> {
+        if attributes.api == OpenGLES {
+            return Err(ExtensionUnsupported("OpenGL ES"))
+        }
+
+        let context = match attributes.version {
+            None => {
+                OSMesaCreateContextExt(OSMESA_RGBA,
+                                       pixel_format.depth_bits as GLint,
+                                       pixel_format.stencil_bits as GLint,
+                                       0,
+                                       share_context)
+            }
+            Some((major_version, minor_version)) => {
+                let create_context_attribs = "OSMesaCreateContextAttribs".with_c_str(|name| {
+                    OSMesaGetProcAddress(name)
+                });
+                if create_context_attribs.is_null() {
+                    return Err(ExtensionUnsupported("OSMesaCreateContextAttribs"))
+                }
+                let create_context_attribs: OSMesaCreateContextAttribsFn =
+                    mem::transmute(create_context_attribs);
+
+                let profile = if attributes.wants_core_profile() {
+                    OSMESA_CORE_PROFILE
+                } else {
+                    OSMESA_COMPAT_PROFILE
+                };
+                let context_attributes = [
+                    OSMESA_FORMAT, OSMESA_RGBA as c_int,
+                    OSMESA_DEPTH_BITS, pixel_format.depth_bits as c_int,
+                    OSMESA_STENCIL_BITS, pixel_format.stencil_bits as c_int,
+                    OSMESA_ACCUM_BITS, 0,
+                    OSMESA_PROFILE, profile,
+                    OSMESA_CONTEXT_MAJOR_VERSION, major_version as c_int,
+                    OSMESA_CONTEXT_MINOR_VERSION, minor_version as c_int,
+                    0
+                ];
+                create_context_attribs(context_attributes.as_ptr(), share_context)
+            }
+        };
+        if context == ptr::mut_null() {
+            debug!("failed to create an OSMesa context for {}", attributes);
+            return Err(ContextCreationFailed)
+        }
+        Ok(context)
+    }
+
+    // Returns the pixel format OSMesa grants for the requested one. The color buffer is always
+    // 8-bit RGBA and single-buffered, and there is no multisampling or sRGB support.
+    fn granted_pixel_format(requested: &PixelFormat) -> PixelFormat {
+        PixelFormat {
+            red_bits: 8,
+            green_bits: 8,
+            blue_bits: 8,
+            alpha_bits: 8,
+            depth_bits: match requested.depth_bits {
+                0 => 0,
+                1...16 => 16,
+                17...24 => 24,
+                _ => 32,
+            },
+            stencil_bits: if requested.stencil_bits > 0 { 8 } else { 0 },
+            samples: 0,
+            srgb: false,
+            double_buffered: false,
+        }
+    }
+
+    // Returns a private buffer large enough to hold RGBA pixels of the given size.
+    fn private_buffer(size: Size2D<int>) -> ColorBuffer {
+        PrivateBuffer(Vec::from_elem((size.width * size.height * 4) as uint, 0u8))
+    }
+
+    /// Reads back the contents of the color buffer as tightly packed RGBA pixels, bottom row
+    /// first. Rendering is finished first.
+    pub fn read_pixels(&self) -> Vec<u8> {
+        gl2::finish();
+        let backing = self.resources.backing.lock();
+        let len = (backing.size.width * backing.size.height * 4) as uint;
+        match backing.buffer {
+            PrivateBuffer(ref buffer) => buffer.slice_to(len).to_vec(),
+            SharedBuffer(ref memory) => memory.as_slice().slice_to(len).to_vec(),
+            ForeignBuffer(buffer) => unsafe {
+                slice::raw::buf_as_slice(buffer as *const u8, len, |pixels| pixels.to_vec())
+            },
+        }
+    }
+
+    // Makes this context current on a new buffer of the given size, so that OSMesa lets go of the
+    // old buffer before it is freed. If OSMesa rejects the new buffer, it keeps rendering into the
+    // old one, which is kept.
+    fn replace_buffer(&self, mut buffer: ColorBuffer, size: Size2D<int>) -> Result<(),Error> {
+        let mut backing = self.resources.backing.lock();
+        unsafe {
+            let result = OSMesaMakeCurrent(*self.context,
+                                           buffer.as_mut_ptr(),
+                                           UNSIGNED_BYTE,
+                                           size.width as GLsizei,
+                                           size.height as GLsizei);
+            if result == 0 {
+                return Err(MakeCurrentFailed)
+            }
+        }
+        let _old_backing = mem::replace(&mut *backing, Backing {
+            buffer: buffer,
+            size: size,
+        });
+        current_context.replace(Some(CurrentContext::new(&self.resources, &self.context)));
+        Ok(())
+    }
+}
+
+local_data_key!(current_context: CurrentContext<NativeResources, OSMesaContext>)
+
+impl GraphicsContextMethods<OSMesaContext> for GraphicsContext {
+    /// Wraps the given instance of the native OSMesa context, bumping the reference count in the
+    /// process. The wrapped context is not destroyed when this `GraphicsContext` goes away.
+    fn wrap(instance: Arc<OSMesaContext>) -> GraphicsContext {
+        let size = Size2D(1, 1);
+        GraphicsContext {
+            resources: Arc::new(NativeResources {
+                backing: Mutex::new(Backing {
+                    buffer: GraphicsContext::private_buffer(size),
+                    size: size,
+                }),
+                pixel_format:
+                    GraphicsContext::granted_pixel_format(&default_attributes().pixel_format),
+                owned_context: None,
+                _buffer_resource: TrackedResource::new(DrawableResource),
+                _context_resource: None,
+            }),
+            context: instance.clone(),
+            _no_sync: marker::NoSync,
+        }
+    }
+
+    /// Returns the underlying native 3D context. The native context is only valid for as long as
+    /// this context or one of its clones is alive.
+    fn native(&self) -> Arc<OSMesaContext> {
+        self.context.clone()
+    }
+
+    /// Creates a new offscreen 3D graphics context with a 24-bit depth and 8-bit stencil buffer.
+    fn try_new() -> Result<GraphicsContext,Error> {
+        GraphicsContextMethods::try_new_with_attributes(&default_attributes())
+    }
+
+    /// Creates a new offscreen 3D graphics context with a 24-bit depth and 8-bit stencil buffer,
+    /// shared with this context.
+    fn try_new_shared(&self) -> Result<GraphicsContext,Error> {
+        self.try_new_shared_with_attributes(&default_attributes())
+    }
+
+    /// Creates a new offscreen 3D graphics context with the given attributes.
+    fn try_new_with_attributes(attributes: &ContextAttributes) -> Result<GraphicsContext,Error> {
+        let size = Size2D(1, 1);
+        GraphicsContext::new_possibly_shared(None,
+                                             attributes,
+                                             GraphicsContext::private_buffer(size),
+                                             size)
+    }
+
+    /// Creates a new offscreen 3D graphics context with the given attributes, shared with this
+    /// context.
+    fn try_new_shared_with_attributes(&self, attributes: &ContextAttributes)
+                                      -> Result<GraphicsContext,Error> {
+        let size = Size2D(1, 1);
+        GraphicsContext::new_possibly_shared(Some(self),
+                                             attributes,
+                                             GraphicsContext::private_buffer(size),
+                                             size)
+    }
+
+    /// Makes this context the current context.
+    fn try_make_current(&self) -> Result<(),Error> {
+        let mut backing = self.resources.backing.lock();
+        unsafe {
+            let result = OSMesaMakeCurrent(*self.context,
+                                           backing.buffer.as_mut_ptr(),
+                                           UNSIGNED_BYTE,
+                                           backing.size.width as GLsizei,
+                                           backing.size.height as GLsizei);
+            if result == 0 {
+                return Err(MakeCurrentFailed)
+            }
+            current_context.replace(Some(CurrentContext::new(&self.resources, &self.context)));
+            Ok(())
+        }
+    }
+
+    fn try_release_current(&self) -> Result<(),Error> {
+        if !self.is_current() {
+            return Ok(())
+        }
+        unsafe {
+            let result = OSMesaMakeCurrent(ptr::mut_null(), ptr::mut_null(), 0, 0, 0);
+            if result == 0 {
+                return Err(MakeCurrentFailed)
+            }
+            current_context.replace(None);
+            Ok(())
+        }
+    }
+
+    fn is_current(&self) -> bool {
+        unsafe {
+            OSMesaGetCurrentContext() == *self.context
+        }
+    }
+
+    /// Returns the current context. Contexts this task made current are returned along with their
+    /// resources; any other OSMesa context is returned with the buffer it is rendering into, which
+    /// is not freed when the context goes away.
+    fn current() -> Option<GraphicsContext> {
+        let native = unsafe { OSMesaGetCurrentContext() };
+        if native == ptr::mut_null() {
+            return None
+        }
+        match current_context.get().and_then(|current| current.upgrade(&native)) {
+            Some((resources, context)) => {
+                return Some(GraphicsContext {
+                    resources: resources,
+                    context: context,
+                    _no_sync: marker::NoSync,
+                })
+            }
+            None => {}
+        }
+
+        let (mut width, mut height, mut format) = (0, 0, 0);
+        let mut buffer = ptr::mut_null();
+        let result = unsafe {
+            OSMesaGetColorBuffer(native, &mut width, &mut height, &mut format, &mut buffer)
+        };
+        if result == 0 {
+            return Some(GraphicsContextMethods::wrap(Arc::new(native)))
+        }
+        Some(GraphicsContext {
+            resources: Arc::new(NativeResources {
+                backing: Mutex::new(Backing {
+                    buffer: ForeignBuffer(buffer),
+                    size: Size2D(width as int, height as int),
+                }),
+                pixel_format:
+                    GraphicsContext::granted_pixel_format(&default_attributes().pixel_format),
+                owned_context: None,
+                _buffer_resource: TrackedResource::new(DrawableResource),
+                _context_resource: None,
+            }),
+            context: Arc::new(native),
+            _no_sync: marker::NoSync,
+        })
+    }
+
+    /// Returns the pixel format OSMesa granted.
+    fn pixel_format(&self) -> PixelFormat {
+        self.resources.pixel_format.clone()
+    }
+
+    fn get_proc_address(&self, name: &str) -> Option<*const c_void> {
+        let address = name.with_c_str(|name| unsafe { OSMesaGetProcAddress(name) });
+        if address.is_null() {
+            None
+        } else {
+            Some(address)
+        }
+    }
+}
+
+impl Clone for GraphicsContext {
+    fn clone(&self) -> GraphicsContext {
+        GraphicsContext {
+            resources: self.resources.clone(),
+            context: self.context.clone(),
+            _no_sync: marker::NoSync,
+        }
+    }
+}
+
+/// An OSMesa context rendering into shared memory. Other processes map the memory through the ID
+/// returned by `id()`; pixels are stored as tightly packed RGBA rows, bottom row first.
+pub struct Context {
+    context: GraphicsContext,
+    id: ShmRegionId,
+    generation: uint,
+    size: Size2D<int>,
+    counter: FrameCounter,
+}
+
+impl Context {
+    /// Returns the graphics context this share context renders with.
+    pub fn graphics_context<'a>(&'a self) -> &'a GraphicsContext {
+        &self.context
+    }
+}
+
+impl ShareContext for Context {
+    fn try_new(size: Size2D<int>) -> Result<Context,Error> {
+        let memory = try!(SharedMemory::create((size.width * size.height * 4) as uint));
+        let id = memory.id();
+        let context = try!(GraphicsContext::new_possibly_shared(None,
+                                                                &default_attributes(),
+                                                                SharedBuffer(memory),
+                                                                size));
+        try!(context.try_make_current());
+
+        Ok(Context {
+            context: context,
+            id: id,
+            generation: 0,
+            size: size,
+            counter: try!(FrameCounter::new()),
+        })
+    }
+
+    /// Moves rendering into a new shared-memory region of the given size. The old region is
+    /// unlinked, though readers that already mapped it keep their mapping.
+    fn try_resize(&mut self, size: Size2D<int>) -> Result<(),Error> {
+        let memory = try!(SharedMemory::create((size.width * size.height * 4) as uint));
+        let id = memory.id();
+        try!(self.context.replace_buffer(SharedBuffer(memory), size));
+        self.id = id;
+        self.generation += 1;
+        self.size = size;
+        Ok(())
+    }
+
+    /// Returns a token for a frame counter that the consumer can wait on without polling the
+    /// color buffer.
+    fn flush(&self) -> SyncToken {
+        // OSMesa renders synchronously into the buffer, so once rendering has finished the
+        // consumer sees the whole frame.
+        self.context.make_current();
+        gl2::finish();
+        self.counter.signal()
+    }
+
+    fn id(&self) -> SurfaceId {
+        ShmId(self.id.clone())
+    }
+
+    fn generation(&self) -> uint {
+        self.generation
+    }
+
+    fn size(&self) -> Size2D<int> {
+        self.size
+    }
+
+    fn format(&self) -> SurfaceFormat {
+        RGBA8Format
+    }
+}
+
+/// Reads a shared-memory surface written by a `Context` in another process, uploading its contents
+/// into a texture owned by the receiving context.
+pub type SurfaceReader = ShmSurfaceReader<GraphicsContext>;
+
+#[test]
+fn clear_color_is_visible_to_readers() {
+    use base::SharedSurfaceReader;
+    use opengles::gl2::COLOR_BUFFER_BIT;
+
+    let share_context: Context = ShareContext::new(Size2D(4, 2));
+    gl2::clear_color(1.0, 0.0, 0.0, 1.0);
+    gl2::clear(COLOR_BUFFER_BIT);
+    share_context.flush().wait().unwrap();
+
+    let expected = Vec::from_fn(4 * 2 * 4, |i| [255u8, 0, 0, 255][i % 4]);
+    assert_eq!(share_context.graphics_context().read_pixels(), expected);
+
+    let receiver: GraphicsContext = GraphicsContextMethods::new();
+    let reader: SurfaceReader = SharedSurfaceReader::open(&receiver,
+                                                          &share_context.id(),
+                                                          Size2D(4, 2));
+    assert_eq!(reader.read_pixels(), expected);
+}
+
+#[test]
+fn gl_errors_are_reported_to_debug_callbacks() {
+    use debug;
+    use debug::HighSeverity;
+
+    let mut attributes: ContextAttributes = Default::default();
+    attributes.debug = true;
+    let context: GraphicsContext = GraphicsContextMethods::new_with_attributes(&attributes);
+    debug::install(&context, HighSeverity).unwrap();
+
+    let ((), messages) = debug::collect_messages(|| {
+        // Not a texture target, so this raises GL_INVALID_ENUM.
+        gl2::bind_texture(UNSIGNED_BYTE, 0);
+    });
+    assert!(messages.iter().any(|message| message.severity == HighSeverity));
+}
+
+#[test]
+fn textures_are_visible_to_shared_contexts() {
+    use opengles::gl2::{COLOR_ATTACHMENT0, FRAMEBUFFER, RGBA, TEXTURE_2D};
+
+    let first: GraphicsContext = GraphicsContextMethods::new();
+    let second = first.new_shared();
+
+    first.make_current();
+    let texture = gl2::gen_textures(1)[0];
+    gl2::bind_texture(TEXTURE_2D, texture);
+    gl2::tex_image_2d(TEXTURE_2D,
+                      0,
+                      RGBA as GLint,
+                      1,
+                      1,
+                      0,
+                      RGBA,
+                      UNSIGNED_BYTE,
+                      Some([ 12u8, 34, 56, 78 ].as_slice()));
+    gl2::finish();
+
+    // Framebuffers are not shared, so read the texture back through one owned by `second`.
+    second.make_current();
+    let framebuffer = gl2::gen_framebuffers(1)[0];
+    gl2::bind_framebuffer(FRAMEBUFFER, framebuffer);
+    gl2::framebuffer_texture_2d(FRAMEBUFFER, COLOR_ATTACHMENT0, TEXTURE_2D, texture, 0);
+    assert_eq!(gl2::read_pixels(0, 0, 1, 1, RGBA, UNSIGNED_BYTE), vec!(12u8, 34, 56, 78));
+
+    gl2::delete_frame_buffers([framebuffer]);
+    gl2::delete_textures([texture]);
+}
+
+#[test]
+fn resized_surfaces_are_announced_with_a_new_id() {
+    use base::SharedSurfaceReader;
+    use opengles::gl2::COLOR_BUFFER_BIT;
+
+    let mut share_context: Context = ShareContext::new(Size2D(2, 2));
+    let old_id = share_context.id();
+    share_context.resize(Size2D(3, 1));
+    assert!(share_context.id() != old_id);
+    assert_eq!(share_context.generation(), 1);
+
+    gl2::clear_color(0.0, 0.0, 1.0, 1.0);
+    gl2::clear(COLOR_BUFFER_BIT);
+    share_context.flush();
+
+    let receiver: GraphicsContext = GraphicsContextMethods::new();
+    let reader: SurfaceReader = SharedSurfaceReader::open(&receiver,
+                                                          &share_context.id(),
+                                                          Size2D(3, 1));
+    assert_eq!(reader.read_pixels(), Vec::from_fn(3 * 4, |i| [0u8, 0, 255, 255][i % 4]));
+}
+
+#[test]
+fn failed_resizes_keep_the_old_surface() {
+    let mut share_context: Context = ShareContext::new(Size2D(2, 2));
+    let id = share_context.id();
+
+    // OSMesa refuses buffers wider than 16384 pixels.
+    assert!(share_context.try_resize(Size2D(20000, 1)).is_err());
+    assert_eq!(share_context.id(), id);
+    assert_eq!(share_context.size(), Size2D(2, 2));
+    assert_eq!(share_context.generation(), 0);
+    assert!(share_context.graphics_context().is_current());
+}
+
+#[test]
+fn current_guards_restore_foreign_contexts_onto_their_own_buffer() {
+    use context::CurrentGuard;
+
+    let mut buffer = Vec::from_elem(2 * 2 * 4, 0u8);
+    let foreign = unsafe { OSMesaCreateContextExt(OSMESA_RGBA, 0, 0, 0, ptr::mut_null()) };
+    assert!(foreign != ptr::mut_null());
+    unsafe {
+        assert!(OSMesaMakeCurrent(foreign, buffer.as_mut_ptr() as *mut c_void, UNSIGNED_BYTE, 2, 2)
+                != 0);
+    }
+
+    let context: GraphicsContext = GraphicsContextMethods::new();
+    drop(CurrentGuard::new(&context).unwrap());
+
+    let (mut width, mut height, mut format) = (0, 0, 0);
+    let mut current_buffer = ptr::mut_null();
+    unsafe {
+        assert_eq!(OSMesaGetCurrentContext(), foreign);
+        OSMesaGetColorBuffer(foreign, &mut width, &mut height, &mut format, &mut current_buffer);
+        assert_eq!(current_buffer, buffer.as_mut_ptr() as *mut c_void);
+        assert_eq!((width, height), (2, 2));
+
+        OSMesaMakeCurrent(ptr::mut_null(), ptr::mut_null(), 0, 0, 0);
+        OSMesaDestroyContext(foreign);
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Surface sharing by copying through shared memory, for when the platform cannot share GPU
+//! surfaces between processes.
+//!
+//! A `ReadbackContext` wraps a share context from any backend. Each `flush()` reads the
+//! framebuffer back into a POSIX shared-memory region named by a `ShmId`, and a
+//! `ShmSurfaceReader` in the consuming process maps the region and uploads it into a texture
+//! owned by its own context. Pixels are stored as tightly packed RGBA rows, bottom row first.
+//!
+//! Each surface has a single region, so a frame flushed while a reader is uploading the previous
+//! one tears. Producers should not flush again until the consumer has waited on the last frame's
+//! token and called `update()`.
+
+use base::{RGBA8Format, ShareContext, SharedSurfaceReader, ShmId, SurfaceFormat, SurfaceId};
+use context::GraphicsContextMethods;
+use error::{Error, IncompatibleSurface, MakeCurrentFailed};
+use fence::SyncToken;
+use resources::{TextureResource, TrackedResource};
+use shm::{FrameCounter, SharedMemory};
+
+use geom::size::Size2D;
+use opengles::gl2::{FRAMEBUFFER, GLint, GLsizei, GLuint, LINEAR, NEAREST, RGBA, TEXTURE_2D};
+use opengles::gl2::{TEXTURE_MAG_FILTER, TEXTURE_MIN_FILTER, UNSIGNED_BYTE};
+use opengles::gl2;
+use std::ptr;
+
+// Returns the number of bytes a surface of the given size occupies in shared memory.
+fn buffer_len(size: Size2D<int>) -> uint {
+    (size.width * size.height * 4) as uint
+}
+
+/// A shared-memory region that frames are copied into, together with the counter that tells
+/// readers when each copy has landed.
+pub struct ShmSurface {
+    memory: SharedMemory,
+    counter: FrameCounter,
+}
+
+impl ShmSurface {
+    /// Creates a region for frames of the given size, or returns the reason it could not be
+    /// created.
+    pub fn try_new(size: Size2D<int>) -> Result<ShmSurface,Error> {
+        Ok(ShmSurface {
+            memory: try!(SharedMemory::create(buffer_len(size))),
+            counter: try!(FrameCounter::new()),
+        })
+    }
+
+    /// Returns the ID that a `ShmSurfaceReader` opens the region with.
+    pub fn id(&self) -> SurfaceId {
+        ShmId(self.memory.id())
+    }
+
+    /// Copies a frame of the given size from the framebuffer bound in the current context and
+    /// returns a token for the frame counter. Reading the pixels waits for rendering to finish, so
+    /// the context need not be flushed first.
+    pub fn copy_from_framebuffer(&self, size: Size2D<int>) -> SyncToken {
+        let pixels = gl2::read_pixels(0,
+                                      0,
+                                      size.width as GLsizei,
+                                      size.height as GLsizei,
+                                      RGBA,
+                                      UNSIGNED_BYTE);
+        unsafe {
+            ptr::copy_nonoverlapping_memory(self.memory.as_mut_ptr(),
+                                            pixels.as_ptr(),
+                                            buffer_len(size));
+        }
+        self.counter.signal()
+    }
+}
+
+/// A share context whose frames are copied into shared memory when flushed. This works with every
+/// backend, at the cost of a readback and an upload per frame.
+pub struct ReadbackContext<S,G> {
+    context: S,
+    // The graphics context the share context renders with, made current to read frames back.
+    graphics_context: G,
+    surface: ShmSurface,
+    generation: uint,
+}
+
+impl<N,S:ShareContext,G:GraphicsContextMethods<N>> ReadbackContext<S,G> {
+    /// Wraps a share context, given the graphics context it renders with, or returns the reason the
+    /// shared memory could not be created.
+    pub fn try_wrap(context: S, graphics_context: G) -> Result<ReadbackContext<S,G>,Error> {
+        let surface = try!(ShmSurface::try_new(context.size()));
+        Ok(ReadbackContext {
+            context: context,
+            graphics_context: graphics_context,
+            surface: surface,
+            generation: 0,
+        })
+    }
+
+    /// Returns the share context that is rendered into.
+    pub fn share_context<'a>(&'a self) -> &'a S {
+        &self.context
+    }
+}
+
+impl<N,S:ShareContext,G:GraphicsContextMethods<N>> ShareContext for ReadbackContext<S,G> {
+    /// Creates a share context and wraps it. Share contexts are current once created, which is how
+    /// the graphics context they render with is found.
+    fn try_new(size: Size2D<int>) -> Result<ReadbackContext<S,G>,Error> {
+        let context: S = try!(ShareContext::try_new(size));
+        let graphics_context: G = match GraphicsContextMethods::current() {
+            Some(graphics_context) => graphics_context,
+            None => return Err(MakeCurrentFailed),
+        };
+        ReadbackContext::try_wrap(context, graphics_context)
+    }
+
+    /// Resizes the wrapped context and moves frames into a new shared-memory region of the given
+    /// size.
+    fn try_resize(&mut self, size: Size2D<int>) -> Result<(),Error> {
+        let surface = try!(ShmSurface::try_new(size));
+        try!(self.context.try_resize(size));
+        self.surface = surface;
+        self.generation += 1;
+        Ok(())
+    }
+
+    /// Makes the wrapped context current, copies its framebuffer into shared memory and returns a
+    /// token for the frame counter.
+    fn flush(&self) -> SyncToken {
+        self.graphics_context.make_current();
+        gl2::bind_framebuffer(FRAMEBUFFER, self.context.framebuffer());
+        self.surface.copy_from_framebuffer(self.context.size())
+    }
+
+    fn id(&self) -> SurfaceId {
+        self.surface.id()
+    }
+
+    fn generation(&self) -> uint {
+        self.generation
+    }
+
+    fn size(&self) -> Size2D<int> {
+        self.context.size()
+    }
+
+    fn format(&self) -> SurfaceFormat {
+        RGBA8Format
+    }
+
+    fn framebuffer(&self) -> GLuint {
+        self.context.framebuffer()
+    }
+}
+
+/// Reads a shared-memory surface written by a `ReadbackContext`, an OSMesa share context or an EGL
+/// share context without dma-buf export in another process, uploading its contents into a
+/// texture owned by the receiving context.
+pub struct ShmSurfaceReader<G> {
+    context: G,
+    memory: SharedMemory,
+    texture: GLuint,
+    size: Size2D<int>,
+    _texture_resource: TrackedResource,
+}
+
+impl<N,G:GraphicsContextMethods<N> + Clone> SharedSurfaceReader<G> for ShmSurfaceReader<G> {
+    fn try_open(context: &G, id: &SurfaceId, size: Size2D<int>)
+                -> Result<ShmSurfaceReader<G>,Error> {
+        let id = match *id {
+            ShmId(ref id) => id,
+            _ => return Err(IncompatibleSurface),
+        };
+        let memory = try!(SharedMemory::open(id, buffer_len(size)));
+        try!(context.try_make_current());
+
+        let texture = gl2::gen_textures(1)[0];
+        let reader = ShmSurfaceReader {
+            context: context.clone(),
+            memory: memory,
+            texture: texture,
+            size: size,
+            _texture_resource: TrackedResource::new(TextureResource),
+        };
+        gl2::bind_texture(TEXTURE_2D, texture);
+        gl2::tex_parameter_i(TEXTURE_2D, TEXTURE_MAG_FILTER, LINEAR as GLint);
+        gl2::tex_parameter_i(TEXTURE_2D, TEXTURE_MIN_FILTER, NEAREST as GLint);
+        reader.upload();
+        Ok(reader)
+    }
+
+    fn texture(&self) -> GLuint {
+        self.texture
+    }
+
+    fn update(&self) {
+        self.context.make_current();
+        gl2::bind_texture(TEXTURE_2D, self.texture);
+        self.upload();
+    }
+
+    fn read_pixels(&self) -> Vec<u8> {
+        self.memory.as_slice().to_vec()
+    }
+}
+
+impl<G> ShmSurfaceReader<G> {
+    // Copies the shared memory into the bound texture.
+    fn upload(&self) {
+        gl2::tex_image_2d(TEXTURE_2D,
+                          0,
+                          RGBA as GLint,
+                          self.size.width as GLsizei,
+                          self.size.height as GLsizei,
+                          0,
+                          RGBA,
+                          UNSIGNED_BYTE,
+                          Some(self.memory.as_slice()));
+    }
+}
+
+#[unsafe_destructor]
+impl<N,G:GraphicsContextMethods<N>> Drop for ShmSurfaceReader<G> {
+    fn drop(&mut self) {
+        if self.context.try_make_current().is_ok() {
+            gl2::delete_textures([self.texture]);
+        }
+    }
+}
+
+#[cfg(feature="osmesa")]
+#[test]
+fn flushed_frames_are_copied_to_readers() {
+    use osmesa::{Context, GraphicsContext};
+    use opengles::gl2::COLOR_BUFFER_BIT;
+
+    let share_context: ReadbackContext<Context,GraphicsContext> = ShareContext::new(Size2D(4, 2));
+    gl2::clear_color(0.0, 1.0, 0.0, 1.0);
+    gl2::clear(COLOR_BUFFER_BIT);
+    share_context.flush().wait().unwrap();
+
+    let receiver: GraphicsContext = GraphicsContextMethods::new();
+    let reader: ShmSurfaceReader<GraphicsContext> =
+        SharedSurfaceReader::open(&receiver, &share_context.id(), Size2D(4, 2));
+    assert_eq!(reader.read_pixels(), Vec::from_fn(4 * 2 * 4, |i| [0u8, 255, 0, 255][i % 4]));
+}
+
+#[cfg(feature="egl")]
+#[test]
+fn flushes_read_back_the_wrapped_egl_context() {
+    use egl::{Context, GraphicsContext};
+    use opengles::gl2::COLOR_BUFFER_BIT;
+
+    let share_context: Context = ShareContext::new(Size2D(4, 2));
+    let graphics_context = share_context.graphics_context().clone();
+    let share_context = ReadbackContext::try_wrap(share_context, graphics_context).unwrap();
+    gl2::clear_color(0.0, 0.0, 1.0, 1.0);
+    gl2::clear(COLOR_BUFFER_BIT);
+
+    // Flushing has to read from the wrapped context's framebuffer even when another framebuffer
+    // is bound and another context is current.
+    gl2::bind_framebuffer(FRAMEBUFFER, 0);
+    let receiver: GraphicsContext = GraphicsContextMethods::new();
+    receiver.make_current();
+    share_context.flush().wait().unwrap();
+
+    let reader: ShmSurfaceReader<GraphicsContext> =
+        SharedSurfaceReader::open(&receiver, &share_context.id(), Size2D(4, 2));
+    assert_eq!(reader.read_pixels(), Vec::from_fn(4 * 2 * 4, |i| [0u8, 0, 255, 255][i % 4]));
+}
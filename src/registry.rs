@@ -0,0 +1,225 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Runtime selection among the backends that were compiled in.
+//!
+//! Which backends exist is decided by cargo features; this module lets an embedder pick one of
+//! them at runtime, by name or through the `SHAREGL_BACKEND` environment variable, and create
+//! share contexts without naming the backend's types.
+
+use base::{ShareContext, SurfaceDescriptor, SurfaceId};
+use error::Error;
+use fence::SyncToken;
+
+use geom::size::Size2D;
+use std::os;
+
+/// The backends this crate can be built with.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Backend {
+    /// IOSurfaces on Mac.
+    IOSurfaceBackend,
+    /// GLX and X pixmaps on Linux.
+    GlxBackend,
+    /// Headless EGL on Linux.
+    EglBackend,
+    /// OSMesa software rendering on Linux.
+    OSMesaBackend,
+    /// The backend that renders nowhere.
+    DummyBackend,
+}
+
+impl Backend {
+    /// Returns the name of the backend, which is also the name of the cargo feature that builds
+    /// it.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            IOSurfaceBackend => "iosurface",
+            GlxBackend => "glx",
+            EglBackend => "egl",
+            OSMesaBackend => "osmesa",
+            DummyBackend => "dummy",
+        }
+    }
+}
+
+/// A share context from any backend.
+pub trait SharedSurface {
+    /// Reallocates the shared surface at the given size, or returns the reason it could not be
+    /// resized.
+    fn try_resize(&mut self, size: Size2D<int>) -> Result<(),Error>;
+
+    /// Submits the rendering done so far and returns the token the consumer waits on.
+    fn flush(&self) -> SyncToken;
+
+    /// Returns the ID that can be passed to other processes to access the shared resources.
+    fn id(&self) -> SurfaceId;
+
+    /// Returns the number of times the surface has been reallocated.
+    fn generation(&self) -> uint;
+
+    /// Returns a description of the shared surface to send to consumers.
+    fn descriptor(&self) -> SurfaceDescriptor;
+}
+
+impl<T:ShareContext> SharedSurface for T {
+    fn try_resize(&mut self, size: Size2D<int>) -> Result<(),Error> {
+        ShareContext::try_resize(self, size)
+    }
+
+    fn flush(&self) -> SyncToken {
+        ShareContext::flush(self)
+    }
+
+    fn id(&self) -> SurfaceId {
+        ShareContext::id(self)
+    }
+
+    fn generation(&self) -> uint {
+        ShareContext::generation(self)
+    }
+
+    fn descriptor(&self) -> SurfaceDescriptor {
+        ShareContext::descriptor(self)
+    }
+}
+
+/// A backend that was compiled in.
+pub struct BackendInfo {
+    /// Which backend this is.
+    pub backend: Backend,
+    new_share_context: fn(Size2D<int>) -> Result<Box<SharedSurface + 'static>,Error>,
+}
+
+impl BackendInfo {
+    /// Creates a share context of the given size on this backend.
+    pub fn new_share_context(&self, size: Size2D<int>)
+                             -> Result<Box<SharedSurface + 'static>,Error> {
+        (self.new_share_context)(size)
+    }
+}
+
+fn new_share_context<T:ShareContext + 'static>(size: Size2D<int>)
+                                               -> Result<Box<SharedSurface + 'static>,Error> {
+    let context: T = try!(ShareContext::try_new(size));
+    Ok(box context as Box<SharedSurface + 'static>)
+}
+
+/// Returns the backends that were compiled in, in order of preference: native backends first,
+/// then headless ones, then the dummy backend.
+pub fn backends() -> Vec<BackendInfo> {
+    let mut backends = Vec::new();
+    register_iosurface(&mut backends);
+    register_glx(&mut backends);
+    register_egl(&mut backends);
+    register_osmesa(&mut backends);
+    register_dummy(&mut backends);
+    backends
+}
+
+/// Returns the compiled-in backend with the given name, if there is one.
+pub fn find(name: &str) -> Option<BackendInfo> {
+    backends().into_iter().find(|info| info.backend.name() == name)
+}
+
+/// Returns the backend named by the `SHAREGL_BACKEND` environment variable, or the most preferred
+/// compiled-in backend if the variable is unset. Returns `None` if the variable names a backend
+/// that was not compiled in.
+pub fn select() -> Option<BackendInfo> {
+    match os::getenv("SHAREGL_BACKEND") {
+        Some(name) => find(name.as_slice()),
+        None => backends().into_iter().next(),
+    }
+}
+
+#[cfg(all(target_os="macos", feature="iosurface"))]
+fn register_iosurface(backends: &mut Vec<BackendInfo>) {
+    use platform;
+    backends.push(BackendInfo {
+        backend: IOSurfaceBackend,
+        new_share_context: new_share_context::<platform::Context>,
+    })
+}
+
+#[cfg(not(all(target_os="macos", feature="iosurface")))]
+fn register_iosurface(_: &mut Vec<BackendInfo>) {}
+
+#[cfg(all(target_os="linux", feature="glx"))]
+fn register_glx(backends: &mut Vec<BackendInfo>) {
+    use platform;
+    backends.push(BackendInfo {
+        backend: GlxBackend,
+        new_share_context: new_share_context::<platform::Context>,
+    })
+}
+
+#[cfg(not(all(target_os="linux", feature="glx")))]
+fn register_glx(_: &mut Vec<BackendInfo>) {}
+
+#[cfg(all(target_os="linux", feature="egl"))]
+fn register_egl(backends: &mut Vec<BackendInfo>) {
+    use egl;
+    backends.push(BackendInfo {
+        backend: EglBackend,
+        new_share_context: new_share_context::<egl::Context>,
+    })
+}
+
+#[cfg(not(all(target_os="linux", feature="egl")))]
+fn register_egl(_: &mut Vec<BackendInfo>) {}
+
+#[cfg(all(target_os="linux", feature="osmesa"))]
+fn register_osmesa(backends: &mut Vec<BackendInfo>) {
+    use osmesa;
+    backends.push(BackendInfo {
+        backend: OSMesaBackend,
+        new_share_context: new_share_context::<osmesa::Context>,
+    })
+}
+
+#[cfg(not(all(target_os="linux", feature="osmesa")))]
+fn register_osmesa(_: &mut Vec<BackendInfo>) {}
+
+#[cfg(any(feature="dummy",
+          not(any(all(target_os="macos", feature="iosurface"),
+                  all(target_os="linux", feature="glx")))))]
+fn register_dummy(backends: &mut Vec<BackendInfo>) {
+    use dummy;
+    backends.push(BackendInfo {
+        backend: DummyBackend,
+        new_share_context: new_share_context::<dummy::Context>,
+    })
+}
+
+#[cfg(not(any(feature="dummy",
+              not(any(all(target_os="macos", feature="iosurface"),
+                      all(target_os="linux", feature="glx"))))))]
+fn register_dummy(_: &mut Vec<BackendInfo>) {}
+
+#[test]
+fn compiled_backends_can_be_found_by_name() {
+    for info in backends().iter() {
+        assert_eq!(find(info.backend.name()).map(|found| found.backend), Some(info.backend));
+    }
+    assert!(find("nonexistent").is_none());
+}
+
+#[cfg(feature="dummy")]
+#[test]
+fn dummy_backend_creates_share_contexts() {
+    use base::DummyId;
+
+    let info = find("dummy").unwrap();
+    let mut context = info.new_share_context(Size2D(10, 10)).unwrap();
+    context.flush();
+    assert!(context.id() != DummyId(0));
+    context.try_resize(Size2D(20, 20)).unwrap();
+    assert_eq!(context.generation(), 1);
+    assert_eq!(context.descriptor().size(), Size2D(20, 20));
+}
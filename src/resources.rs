@@ -0,0 +1,184 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Bookkeeping for the native objects owned by graphics contexts.
+//!
+//! Each backend holds a `TrackedResource` alongside every native object it creates and drops it
+//! when the object is destroyed, so that leaks can be detected from tests on any platform.
+
+use std::sync::atomics::{AtomicInt, SeqCst};
+use sync::Arc;
+
+/// The kinds of native objects that are tracked.
+#[deriving(Clone, PartialEq, Show)]
+pub enum ResourceKind {
+    /// A connection to the windowing system, such as an X `Display`.
+    DisplayResource,
+    /// A native graphics context, such as a `GLXContext` or `CGLContextObj`.
+    ContextResource,
+    /// A drawable that a context renders into, such as a pixmap or an IOSurface.
+    DrawableResource,
+    /// A GL framebuffer object.
+    FramebufferResource,
+    /// A GL texture.
+    TextureResource,
+}
+
+struct Counters {
+    displays: AtomicInt,
+    contexts: AtomicInt,
+    drawables: AtomicInt,
+    framebuffers: AtomicInt,
+    textures: AtomicInt,
+}
+
+impl Counters {
+    fn new() -> Counters {
+        Counters {
+            displays: AtomicInt::new(0),
+            contexts: AtomicInt::new(0),
+            drawables: AtomicInt::new(0),
+            framebuffers: AtomicInt::new(0),
+            textures: AtomicInt::new(0),
+        }
+    }
+
+    fn get<'a>(&'a self, kind: ResourceKind) -> &'a AtomicInt {
+        match kind {
+            DisplayResource => &self.displays,
+            ContextResource => &self.contexts,
+            DrawableResource => &self.drawables,
+            FramebufferResource => &self.framebuffers,
+            TextureResource => &self.textures,
+        }
+    }
+}
+
+// The counters for resources created on this task. Resources remember the counters they were
+// created with, so dropping them on another task still updates the right ones.
+local_data_key!(task_counters: Arc<Counters>)
+
+fn counters() -> Arc<Counters> {
+    match task_counters.get() {
+        Some(counters) => return (*counters).clone(),
+        None => {}
+    }
+    let counters = Arc::new(Counters::new());
+    task_counters.replace(Some(counters.clone()));
+    counters
+}
+
+/// Records that a native object is alive. The object is considered destroyed once this value is
+/// dropped.
+pub struct TrackedResource {
+    kind: ResourceKind,
+    counters: Arc<Counters>,
+}
+
+impl TrackedResource {
+    /// Starts tracking a newly-created native object of the given kind.
+    pub fn new(kind: ResourceKind) -> TrackedResource {
+        let counters = counters();
+        counters.get(kind).fetch_add(1, SeqCst);
+        TrackedResource {
+            kind: kind,
+            counters: counters,
+        }
+    }
+
+    /// Returns the kind of object being tracked.
+    pub fn kind(&self) -> ResourceKind {
+        self.kind
+    }
+}
+
+impl Drop for TrackedResource {
+    fn drop(&mut self) {
+        self.counters.get(self.kind).fetch_sub(1, SeqCst);
+    }
+}
+
+/// Returns the number of objects of the given kind that were created on this task and are still
+/// alive.
+pub fn live_count(kind: ResourceKind) -> int {
+    counters().get(kind).load(SeqCst)
+}
+
+#[test]
+fn tracked_resources_are_counted_until_dropped() {
+    let display = TrackedResource::new(DisplayResource);
+    let first = TrackedResource::new(TextureResource);
+    let second = TrackedResource::new(TextureResource);
+    assert_eq!(live_count(DisplayResource), 1);
+    assert_eq!(live_count(TextureResource), 2);
+    assert_eq!(live_count(FramebufferResource), 0);
+
+    drop(first);
+    assert_eq!(live_count(TextureResource), 1);
+    drop(second);
+    drop(display);
+    assert_eq!(live_count(TextureResource), 0);
+    assert_eq!(live_count(DisplayResource), 0);
+}
+
+#[test]
+fn shared_owners_release_once() {
+    let resource = Arc::new(TrackedResource::new(ContextResource));
+    let clone = resource.clone();
+    drop(resource);
+    assert_eq!(live_count(ContextResource), 1);
+    drop(clone);
+    assert_eq!(live_count(ContextResource), 0);
+}
+
+#[test]
+fn resources_dropped_on_another_task_are_released() {
+    let resource = TrackedResource::new(DrawableResource);
+    assert_eq!(live_count(DrawableResource), 1);
+
+    let (sender, receiver) = channel();
+    spawn(proc() {
+        drop(resource);
+        sender.send(());
+    });
+    receiver.recv();
+    assert_eq!(live_count(DrawableResource), 0);
+}
+
+#[cfg(feature="osmesa")]
+#[test]
+fn dropping_osmesa_objects_releases_their_resources() {
+    use base::{ShareContext, SharedSurfaceReader};
+    use context::GraphicsContextMethods;
+    use geom::size::Size2D;
+    use osmesa::{Context, GraphicsContext, SurfaceReader};
+
+    let context: GraphicsContext = GraphicsContextMethods::new();
+    assert_eq!(live_count(ContextResource), 1);
+    assert_eq!(live_count(DrawableResource), 1);
+    drop(context);
+    assert_eq!(live_count(ContextResource), 0);
+    assert_eq!(live_count(DrawableResource), 0);
+
+    let share_context: Context = ShareContext::new(Size2D(4, 4));
+    let receiver: GraphicsContext = GraphicsContextMethods::new();
+    let reader: SurfaceReader =
+        SharedSurfaceReader::open(&receiver, &share_context.id(), Size2D(4, 4));
+    assert_eq!(live_count(ContextResource), 2);
+    assert_eq!(live_count(TextureResource), 1);
+    drop(reader);
+    assert_eq!(live_count(TextureResource), 0);
+    drop(share_context);
+    drop(receiver);
+
+    for &kind in [ DisplayResource, ContextResource, DrawableResource, FramebufferResource,
+                   TextureResource ].iter() {
+        assert_eq!(live_count(kind), 0);
+    }
+}
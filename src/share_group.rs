@@ -0,0 +1,151 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Groups of contexts that share textures, buffers and other GL objects.
+
+use attributes::ContextAttributes;
+use context::GraphicsContextMethods;
+use error::Error;
+
+use std::sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+use sync::{Arc, Mutex};
+
+// The source of group IDs. Zero is never handed out.
+static mut next_group_id: AtomicUint = INIT_ATOMIC_UINT;
+
+struct ShareGroupState<C> {
+    id: uint,
+    // The attributes siblings are created with, or `None` for the backend's defaults.
+    attributes: Option<ContextAttributes>,
+    // Every context in the group is created shared with the root, which is kept alive until the
+    // group handle and every sibling have gone away. The lock also keeps siblings from being
+    // created concurrently against the same root.
+    root: Mutex<C>,
+}
+
+/// A root context and any number of sibling contexts that share GL objects with it, typically one
+/// per worker thread. The root is not destroyed while the group or any of its contexts are alive.
+pub struct ShareGroup<C> {
+    state: Arc<ShareGroupState<C>>,
+}
+
+impl<N,C:GraphicsContextMethods<N> + Send> ShareGroup<C> {
+    /// Creates a group around a new root context with the given attributes, or returns the reason
+    /// the root could not be created. Siblings are created with the same attributes.
+    pub fn try_new_with_attributes(attributes: &ContextAttributes) -> Result<ShareGroup<C>,Error> {
+        let root = try!(GraphicsContextMethods::try_new_with_attributes(attributes));
+        Ok(ShareGroup::from_root(root, attributes))
+    }
+
+    /// Creates a group around a new root context with the backend's default attributes, or returns
+    /// the reason the root could not be created. Siblings use the same defaults.
+    pub fn try_new() -> Result<ShareGroup<C>,Error> {
+        let root = try!(GraphicsContextMethods::try_new());
+        Ok(ShareGroup::new_with_root(root, None))
+    }
+
+    /// Creates a group around an existing context. Siblings are created with the given attributes,
+    /// which should match the ones the root was created with.
+    pub fn from_root(root: C, attributes: &ContextAttributes) -> ShareGroup<C> {
+        ShareGroup::new_with_root(root, Some(attributes.clone()))
+    }
+
+    fn new_with_root(root: C, attributes: Option<ContextAttributes>) -> ShareGroup<C> {
+        ShareGroup {
+            state: Arc::new(ShareGroupState {
+                id: unsafe { next_group_id.fetch_add(1, SeqCst) + 1 },
+                attributes: attributes,
+                root: Mutex::new(root),
+            }),
+        }
+    }
+
+    /// Returns an ID that is unique among all groups in this process.
+    pub fn id(&self) -> uint {
+        self.state.id
+    }
+
+    /// Creates a new context in this group, or returns the reason it could not be created. The
+    /// context is not made current.
+    pub fn try_new_context(&self) -> Result<GroupContext<C>,Error> {
+        let root = self.state.root.lock();
+        let context = try!(match self.state.attributes {
+            Some(ref attributes) => root.try_new_shared_with_attributes(attributes),
+            None => root.try_new_shared(),
+        });
+        Ok(GroupContext {
+            context: context,
+            group: self.clone(),
+        })
+    }
+
+    /// Creates a new context in this group. Fails if the context cannot be created.
+    pub fn new_context(&self) -> GroupContext<C> {
+        match self.try_new_context() {
+            Ok(context) => context,
+            Err(error) => {
+                fail!("failed to create a context in share group {}: {}", self.id(), error)
+            }
+        }
+    }
+}
+
+impl<C:Send> Clone for ShareGroup<C> {
+    fn clone(&self) -> ShareGroup<C> {
+        ShareGroup {
+            state: self.state.clone(),
+        }
+    }
+}
+
+/// A context created by a `ShareGroup`. It keeps the group's root context alive, and can be sent
+/// to the thread that will use it.
+pub struct GroupContext<C> {
+    context: C,
+    group: ShareGroup<C>,
+}
+
+impl<C:Send> GroupContext<C> {
+    /// Returns the graphics context.
+    pub fn graphics_context<'a>(&'a self) -> &'a C {
+        &self.context
+    }
+
+    /// Returns the group this context belongs to.
+    pub fn group<'a>(&'a self) -> &'a ShareGroup<C> {
+        &self.group
+    }
+
+    /// Returns the ID of the group this context belongs to.
+    pub fn group_id(&self) -> uint {
+        self.group.state.id
+    }
+}
+
+#[cfg(feature="dummy")]
+#[test]
+fn contexts_report_their_group_on_any_task() {
+    use dummy::GraphicsContext;
+
+    let group: ShareGroup<GraphicsContext> = ShareGroup::try_new().unwrap();
+    let other_group: ShareGroup<GraphicsContext> = ShareGroup::try_new().unwrap();
+    assert!(group.id() != other_group.id());
+
+    let context = group.new_context();
+    assert_eq!(context.group_id(), group.id());
+
+    let (sender, receiver) = channel();
+    let sibling = group.new_context();
+    drop(group);
+    spawn(proc() {
+        sibling.graphics_context().make_current();
+        sender.send(sibling.group_id());
+    });
+    assert_eq!(receiver.recv(), context.group_id());
+}
@@ -0,0 +1,254 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Named POSIX shared-memory regions, used by the backends that share surfaces through CPU memory.
+
+use base::ShmRegionId;
+use error::{Error, IncompatibleSurface, SurfaceCreationFailed, SyncTimedOut};
+use fence::{CounterSync, SyncToken};
+
+use libc::{c_char, c_int, c_void, close, fstat, ftruncate, getpid, mmap, mode_t, munmap, off_t};
+use libc::{size_t, stat};
+use libc::{MAP_FAILED, MAP_SHARED, O_CREAT, O_EXCL, O_RDONLY, O_RDWR, PROT_READ, PROT_WRITE};
+use std::c_str::ToCStr;
+use std::io::timer;
+use std::mem;
+use std::ptr;
+use std::raw::Slice;
+use std::sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+use std::time::Duration;
+
+#[link(name = "rt")]
+extern {
+    fn shm_open(name: *const c_char, oflag: c_int, mode: mode_t) -> c_int;
+    fn shm_unlink(name: *const c_char) -> c_int;
+}
+
+// The source of region serial numbers in this process.
+static mut next_serial: AtomicUint = INIT_ATOMIC_UINT;
+
+// Returns the name of the shared-memory object with the given ID.
+fn name_for_id(id: &ShmRegionId) -> String {
+    format!("/sharegl-{}-{}", id.pid, id.serial)
+}
+
+/// A region of shared memory, mapped into this process. The creator of a region unlinks it when
+/// the region is dropped; processes that opened it only unmap it.
+pub struct SharedMemory {
+    id: ShmRegionId,
+    ptr: *mut u8,
+    len: uint,
+    creator: bool,
+}
+
+impl SharedMemory {
+    /// Creates and maps a new shared-memory region of the given length. The region's ID is unique
+    /// among all processes on this machine for as long as its creator is alive.
+    pub fn create(len: uint) -> Result<SharedMemory,Error> {
+        let id = ShmRegionId {
+            pid: unsafe { getpid() } as i32,
+            serial: unsafe { next_serial.fetch_add(1, SeqCst) } as u64,
+        };
+        let fd = name_for_id(&id).with_c_str(|name| {
+            unsafe { shm_open(name, O_CREAT | O_EXCL | O_RDWR, 0o600) }
+        });
+        if fd < 0 {
+            debug!("shm_open failed to create {}", name_for_id(&id));
+            return Err(SurfaceCreationFailed)
+        }
+
+        unsafe {
+            if ftruncate(fd, len as off_t) < 0 {
+                close(fd);
+                SharedMemory::unlink(&id);
+                return Err(SurfaceCreationFailed)
+            }
+        }
+
+        match SharedMemory::map(fd, len, true) {
+            Some(ptr) => {
+                Ok(SharedMemory {
+                    id: id,
+                    ptr: ptr,
+                    len: len,
+                    creator: true,
+                })
+            }
+            None => {
+                SharedMemory::unlink(&id);
+                Err(SurfaceCreationFailed)
+            }
+        }
+    }
+
+    /// Maps the region with the given ID, created by `create()` in this or another process, for
+    /// reading. Returns `IncompatibleSurface` if the region is shorter than `len`, since reading
+    /// past its end would raise `SIGBUS`.
+    pub fn open(id: &ShmRegionId, len: uint) -> Result<SharedMemory,Error> {
+        let fd = name_for_id(id).with_c_str(|name| unsafe { shm_open(name, O_RDONLY, 0) });
+        if fd < 0 {
+            debug!("shm_open failed to open {}", name_for_id(id));
+            return Err(SurfaceCreationFailed)
+        }
+
+        unsafe {
+            let mut status: stat = mem::zeroed();
+            if fstat(fd, &mut status) < 0 {
+                close(fd);
+                return Err(SurfaceCreationFailed)
+            }
+            if (status.st_size as uint) < len {
+                debug!("{} holds {} bytes, not {}", name_for_id(id), status.st_size, len);
+                close(fd);
+                return Err(IncompatibleSurface)
+            }
+        }
+
+        match SharedMemory::map(fd, len, false) {
+            Some(ptr) => {
+                Ok(SharedMemory {
+                    id: id.clone(),
+                    ptr: ptr,
+                    len: len,
+                    creator: false,
+                })
+            }
+            None => Err(SurfaceCreationFailed),
+        }
+    }
+
+    /// Returns the ID that other processes pass to `open()` to map this region.
+    pub fn id(&self) -> ShmRegionId {
+        self.id.clone()
+    }
+
+    /// Returns a pointer to the start of the mapping.
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// Returns the contents of the region.
+    pub fn as_slice<'a>(&'a self) -> &'a [u8] {
+        unsafe {
+            mem::transmute(Slice {
+                data: self.ptr as *const u8,
+                len: self.len,
+            })
+        }
+    }
+
+    // Maps the given file descriptor and closes it. The mapping keeps the object alive.
+    fn map(fd: c_int, len: uint, writable: bool) -> Option<*mut u8> {
+        let protection = if writable { PROT_READ | PROT_WRITE } else { PROT_READ };
+        unsafe {
+            let ptr = mmap(ptr::mut_null(), len as size_t, protection, MAP_SHARED, fd, 0);
+            close(fd);
+            if ptr == MAP_FAILED {
+                debug!("mmap of {} bytes failed", len);
+                return None
+            }
+            Some(ptr as *mut u8)
+        }
+    }
+
+    fn unlink(id: &ShmRegionId) {
+        name_for_id(id).with_c_str(|name| unsafe { shm_unlink(name) });
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.ptr as *mut c_void, self.len as size_t);
+        }
+        if self.creator {
+            SharedMemory::unlink(&self.id);
+        }
+    }
+}
+
+/// A count of completed frames in its own shared-memory region, which consumers wait on through
+/// `CounterSync` tokens.
+pub struct FrameCounter {
+    memory: SharedMemory,
+}
+
+impl FrameCounter {
+    /// Creates a counter at zero.
+    pub fn new() -> Result<FrameCounter,Error> {
+        Ok(FrameCounter {
+            memory: try!(SharedMemory::create(mem::size_of::<AtomicUint>())),
+        })
+    }
+
+    /// Records that another frame is complete and returns the token for it.
+    pub fn signal(&self) -> SyncToken {
+        let value = FrameCounter::counter(&self.memory).fetch_add(1, SeqCst) + 1;
+        CounterSync(self.memory.id(), value)
+    }
+
+    /// Blocks until the counter in the region with the given ID reaches the given value, or
+    /// returns `SyncTimedOut` if it has not done so within the timeout.
+    pub fn wait(id: &ShmRegionId, value: uint, timeout: Duration) -> Result<(),Error> {
+        let memory = try!(SharedMemory::open(id, mem::size_of::<AtomicUint>()));
+        let mut remaining = timeout.num_milliseconds();
+        while FrameCounter::counter(&memory).load(SeqCst) < value {
+            if remaining <= 0 {
+                return Err(SyncTimedOut)
+            }
+            timer::sleep(Duration::milliseconds(1));
+            remaining -= 1;
+        }
+        Ok(())
+    }
+
+    fn counter<'a>(memory: &'a SharedMemory) -> &'a AtomicUint {
+        unsafe {
+            &*(memory.as_mut_ptr() as *const AtomicUint)
+        }
+    }
+}
+
+#[test]
+fn regions_can_be_opened_by_id() {
+    let region = SharedMemory::create(16).unwrap();
+    unsafe {
+        *region.as_mut_ptr().offset(3) = 42;
+    }
+
+    let reader = SharedMemory::open(&region.id(), 16).unwrap();
+    assert_eq!(reader.as_slice()[3], 42);
+    assert!(SharedMemory::create(16).unwrap().id() != region.id());
+}
+
+#[test]
+fn regions_cannot_be_opened_past_their_end() {
+    let region = SharedMemory::create(16).unwrap();
+    assert_eq!(SharedMemory::open(&region.id(), 17).err(), Some(IncompatibleSurface));
+}
+
+#[test]
+fn regions_are_unlinked_by_their_creator() {
+    let id = SharedMemory::create(16).unwrap().id();
+    assert!(SharedMemory::open(&id, 16).is_err());
+}
+
+#[test]
+fn counter_tokens_complete_once_signaled() {
+    let counter = FrameCounter::new().unwrap();
+    counter.signal().wait().unwrap();
+    assert_eq!(counter.signal(), CounterSync(counter.memory.id(), 2));
+}
+
+#[test]
+fn waiting_for_an_unsignaled_counter_times_out() {
+    let counter = FrameCounter::new().unwrap();
+    let token = CounterSync(counter.memory.id(), 1);
+    assert_eq!(token.wait_timeout(Duration::milliseconds(5)), Err(SyncTimedOut));
+}
@@ -0,0 +1,247 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Tear-free frame delivery between processes through a rotating set of share contexts.
+//!
+//! The producer renders into the back buffer of a `SwapChain` and calls `present()`, which hands
+//! the finished buffer to the consumer and moves rendering to a free one. The consumer acquires
+//! the presented buffer through a `SwapChainReader`, and when it is done releases it and sends its
+//! ID back, so that the producer never draws into a buffer that is being read. Frames and IDs
+//! travel over whatever channel the embedder already has between the two processes.
+
+use base::{ShareContext, SharedSurfaceReader, SurfaceDescriptor, SurfaceId};
+use error::{BufferNotReleased, Error, NoFreeBuffer};
+use fence::SyncToken;
+
+use geom::size::Size2D;
+
+/// A presented buffer, as sent from the producer to the consumer. The descriptor can be
+/// serialized; the sync token may own a fence, which travels over a Unix socket instead.
+#[deriving(PartialEq, Show)]
+pub struct Frame {
+    /// The buffer the frame was rendered into.
+    pub descriptor: SurfaceDescriptor,
+    /// The token the consumer waits on before reading the buffer.
+    pub sync: SyncToken,
+}
+
+#[deriving(Clone, PartialEq, Show)]
+enum BufferState {
+    // Available to become the back buffer.
+    FreeBuffer,
+    // Being rendered into by the producer.
+    BackBuffer,
+    // Presented to the consumer and not yet released.
+    HeldBuffer,
+}
+
+/// A set of share contexts that the producer renders into in turn.
+///
+/// Each buffer is a separate share context with its own graphics context, so the producer must
+/// make the back buffer's context current before rendering a frame.
+pub struct SwapChain<C> {
+    buffers: Vec<C>,
+    states: Vec<BufferState>,
+    back: uint,
+}
+
+impl<C:ShareContext> SwapChain<C> {
+    /// Creates a swap chain of `count` buffers of the given size, or returns the reason one of
+    /// them could not be created. Two buffers give double buffering and three triple buffering.
+    pub fn try_new(size: Size2D<int>, count: uint) -> Result<SwapChain<C>,Error> {
+        assert!(count >= 2, "a swap chain needs at least two buffers");
+        let mut buffers = Vec::with_capacity(count);
+        for _ in range(0, count) {
+            buffers.push(try!(ShareContext::try_new(size)));
+        }
+        let mut states = Vec::from_elem(count, FreeBuffer);
+        states.as_mut_slice()[0] = BackBuffer;
+        Ok(SwapChain {
+            buffers: buffers,
+            states: states,
+            back: 0,
+        })
+    }
+
+    /// Creates a swap chain of `count` buffers of the given size. Fails if a buffer cannot be
+    /// created.
+    pub fn new(size: Size2D<int>, count: uint) -> SwapChain<C> {
+        match SwapChain::try_new(size, count) {
+            Ok(swap_chain) => swap_chain,
+            Err(error) => fail!("failed to create swap chain: {}", error),
+        }
+    }
+
+    /// Returns the buffer the next frame should be rendered into.
+    pub fn back_buffer<'a>(&'a self) -> &'a C {
+        &self.buffers[self.back]
+    }
+
+    /// Flushes the back buffer, hands it to the consumer and makes a free buffer the new back
+    /// buffer. Returns the frame to send to the consumer, or `NoFreeBuffer` if the consumer still
+    /// holds every other buffer, in which case nothing is presented and the frame can be presented
+    /// again once a buffer has been released.
+    pub fn present(&mut self) -> Result<Frame,Error> {
+        let next = match self.states.iter().position(|state| *state == FreeBuffer) {
+            Some(next) => next,
+            None => return Err(NoFreeBuffer),
+        };
+
+        let presented = &self.buffers[self.back];
+        let frame = Frame {
+            descriptor: presented.descriptor(),
+            sync: presented.flush(),
+        };
+        self.states.as_mut_slice()[self.back] = HeldBuffer;
+        self.states.as_mut_slice()[next] = BackBuffer;
+        self.back = next;
+        Ok(frame)
+    }
+
+    /// Returns the buffer with the given ID, as sent back by the consumer's
+    /// `SwapChainReader::release()`, to the pool of free buffers.
+    pub fn release(&mut self, id: &SurfaceId) {
+        for (buffer, state) in self.buffers.iter().zip(self.states.as_mut_slice().iter_mut()) {
+            if buffer.id() == *id && *state == HeldBuffer {
+                *state = FreeBuffer;
+                return
+            }
+        }
+        debug!("ignoring release of buffer {}, which the consumer does not hold", id);
+    }
+}
+
+/// The number of frames after which the reader for a buffer that has not been presented again is
+/// closed. Producers replace their buffers when they resize or recreate their swap chain, and the
+/// old buffers are never presented again.
+pub static MAX_IDLE_FRAMES: uint = 16;
+
+// A reader for one of the producer's buffers, kept across frames.
+struct CachedReader<R> {
+    descriptor: SurfaceDescriptor,
+    reader: R,
+    // The number of the frame this buffer was last acquired for.
+    last_acquired: uint,
+}
+
+/// The consumer's side of a swap chain. Readers are opened on first use and reused for as long as
+/// the producer keeps presenting the same surfaces, and closed once their buffer has not been
+/// presented for `MAX_IDLE_FRAMES` frames.
+pub struct SwapChainReader<G,R> {
+    context: G,
+    readers: Vec<CachedReader<R>>,
+    acquired: Option<SurfaceId>,
+    // The number of frames acquired so far.
+    frame: uint,
+}
+
+impl<G,R:SharedSurfaceReader<G>> SwapChainReader<G,R> {
+    /// Creates a reader that binds presented buffers to textures in the given context.
+    pub fn new(context: G) -> SwapChainReader<G,R> {
+        SwapChainReader {
+            context: context,
+            readers: Vec::new(),
+            acquired: None,
+            frame: 0,
+        }
+    }
+
+    /// Waits for a frame from `SwapChain::present()` to complete, then acquires its buffer and
+    /// returns its reader, brought up to date, or the reason the frame could not be waited on or
+    /// the buffer opened. Returns `BufferNotReleased` if the previously acquired buffer has not
+    /// been released.
+    pub fn acquire<'a>(&'a mut self, frame: Frame) -> Result<&'a R,Error> {
+        if self.acquired.is_some() {
+            return Err(BufferNotReleased)
+        }
+        let Frame { descriptor, sync } = frame;
+        try!(sync.wait());
+        self.frame += 1;
+
+        // Resized surfaces must be opened again, and buffers that have not been presented for a
+        // while have most likely been replaced.
+        let frame = self.frame;
+        self.readers.retain(|cached| {
+            if cached.descriptor.id == descriptor.id {
+                cached.descriptor.generation == descriptor.generation
+            } else {
+                frame - cached.last_acquired <= MAX_IDLE_FRAMES
+            }
+        });
+        let cached = self.readers.iter().position(|cached| cached.descriptor.id == descriptor.id);
+        let index = match cached {
+            Some(index) => index,
+            None => {
+                let reader = try!(SharedSurfaceReader::try_open(&self.context,
+                                                                &descriptor.id,
+                                                                descriptor.size()));
+                self.readers.push(CachedReader {
+                    descriptor: descriptor.clone(),
+                    reader: reader,
+                    last_acquired: frame,
+                });
+                self.readers.len() - 1
+            }
+        };
+
+        self.readers.as_mut_slice()[index].last_acquired = frame;
+        self.acquired = Some(descriptor.id.clone());
+        let reader = &self.readers[index].reader;
+        reader.update();
+        Ok(reader)
+    }
+
+    /// Releases the acquired buffer and returns its ID, which must be sent back to the producer's
+    /// `SwapChain::release()`. Returns `None` if no buffer is acquired.
+    pub fn release(&mut self) -> Option<SurfaceId> {
+        self.acquired.take()
+    }
+}
+
+#[cfg(feature="dummy")]
+#[test]
+fn presented_buffers_are_reused_once_released() {
+    use dummy::Context;
+
+    let mut swap_chain: SwapChain<Context> = SwapChain::new(Size2D(4, 4), 2);
+    let first = swap_chain.present().unwrap().descriptor;
+    let second = swap_chain.back_buffer().id();
+    assert!(first.id != second);
+
+    // The consumer holds the first buffer, so the second cannot be presented yet.
+    assert!(swap_chain.present() == Err(NoFreeBuffer));
+    swap_chain.release(&first.id);
+    assert_eq!(swap_chain.present().unwrap().descriptor.id, second);
+    assert_eq!(swap_chain.back_buffer().id(), first.id);
+}
+
+#[cfg(feature="osmesa")]
+#[test]
+fn readers_are_closed_once_their_buffers_are_no_longer_presented() {
+    use context::GraphicsContextMethods;
+    use osmesa::{Context, GraphicsContext, SurfaceReader};
+
+    let mut reader: SwapChainReader<GraphicsContext,SurfaceReader> =
+        SwapChainReader::new(GraphicsContextMethods::new());
+    let mut old_swap_chain: SwapChain<Context> = SwapChain::new(Size2D(4, 4), 3);
+    assert!(reader.acquire(old_swap_chain.present().unwrap()).is_ok());
+    let frame = old_swap_chain.present().unwrap();
+    assert_eq!(reader.acquire(frame).err(), Some(BufferNotReleased));
+    reader.release();
+
+    // A new swap chain replaces every buffer, so the old reader goes away after a while.
+    let mut swap_chain: SwapChain<Context> = SwapChain::new(Size2D(4, 4), 3);
+    for _ in range(0, MAX_IDLE_FRAMES + 1) {
+        assert!(reader.acquire(swap_chain.present().unwrap()).is_ok());
+        swap_chain.release(&reader.release().unwrap());
+    }
+    assert!(reader.readers.iter().all(|cached| {
+        swap_chain.buffers.iter().any(|buffer| buffer.id() == cached.descriptor.id)
+    }));
+}
@@ -0,0 +1,70 @@
+// Copyright 2013 The Servo Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Dma-bufs backed by ordinary memory through the udmabuf driver, so that tests can exercise
+//! dma-buf import on machines without a GPU.
+
+use fd::FileDescriptor;
+
+use libc::{O_RDWR, c_char, c_int, c_uint, c_ulong, c_void, ftruncate, off_t, open, size_t};
+use libc::{ssize_t, write};
+use std::c_str::ToCStr;
+
+#[repr(C)]
+struct UdmabufCreate {
+    memfd: u32,
+    flags: u32,
+    offset: u64,
+    size: u64,
+}
+
+static MFD_ALLOW_SEALING: c_uint = 0x2;
+static F_ADD_SEALS: c_int = 1033;
+static F_SEAL_SHRINK: c_int = 0x2;
+static UDMABUF_CREATE: c_ulong = 0x40187542;
+static PAGE_SIZE: uint = 4096;
+
+extern {
+    fn memfd_create(name: *const c_char, flags: c_uint) -> c_int;
+    fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+}
+
+/// Creates a dma-buf holding the given bytes, padded to whole pages. Returns `None` if the
+/// machine has no udmabuf driver.
+pub fn create(contents: &[u8]) -> Option<FileDescriptor> {
+    let device = "/dev/udmabuf".with_c_str(|path| unsafe { open(path, O_RDWR, 0) });
+    if device < 0 {
+        return None
+    }
+    let device = FileDescriptor::from_raw_fd(device as int);
+
+    let len = (contents.len() + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+    unsafe {
+        let memfd = "sharegl-udmabuf".with_c_str(|name| memfd_create(name, MFD_ALLOW_SEALING));
+        assert!(memfd >= 0);
+        let memfd = FileDescriptor::from_raw_fd(memfd as int);
+        let raw_memfd = memfd.as_raw_fd() as c_int;
+        assert_eq!(ftruncate(raw_memfd, len as off_t), 0);
+        assert_eq!(write(raw_memfd, contents.as_ptr() as *const c_void, contents.len() as size_t),
+                   contents.len() as ssize_t);
+
+        // The driver only accepts memory that can no longer shrink.
+        assert_eq!(fcntl(raw_memfd, F_ADD_SEALS, F_SEAL_SHRINK), 0);
+        let mut create = UdmabufCreate {
+            memfd: raw_memfd as u32,
+            flags: 0,
+            offset: 0,
+            size: len as u64,
+        };
+        let fd = ioctl(device.as_raw_fd() as c_int, UDMABUF_CREATE, &mut create);
+        assert!(fd >= 0);
+        Some(FileDescriptor::from_raw_fd(fd as int))
+    }
+}